@@ -1,117 +1,418 @@
 use std::{
     collections::HashMap,
-    io::{ErrorKind, Write},
-    net::{TcpStream, ToSocketAddrs},
-    sync::mpsc,
+    io::{Error, ErrorKind},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{mpsc, Arc, Mutex, OnceLock},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use crate::events::{PaxosAcceptedValue, PaxosAcceptorEvent, PaxosProposerEvent};
+use crate::{
+    codec::CodecKind,
+    events::{Envelope, EnvelopeError, PaxosAcceptedValue, PaxosAcceptorEvent, PaxosProposerEvent},
+    framing::{read_frame, write_frame},
+    handle_buffer,
+    pool::{connection_pool, IDLE_TIMEOUT},
+    shutdown::Shutdown,
+};
 
 pub trait P2PSend {
     const TIMEOUT: Duration = Duration::from_secs(5);
 
-    fn send(to_addr: &String, buffer: &[u8]) -> std::io::Result<usize> {
+    /// Resolves `to_addr`, waiting at most `TIMEOUT` for DNS. Successful
+    /// lookups are cached until a connection to the address fails.
+    fn resolve(to_addr: &str) -> std::io::Result<SocketAddr> {
+        if let Ok(addr) = to_addr.parse() {
+            return Ok(addr);
+        }
+        if let Some(addr) = resolved_addresses().lock().unwrap().get(to_addr) {
+            return Ok(*addr);
+        }
+
         let (sender, receiver) = mpsc::channel();
 
-        let addr = to_addr.clone();
+        let addr = to_addr.to_owned();
         thread::spawn(move || {
-            match sender.send(addr.to_socket_addrs().unwrap().next().unwrap()) {
-                Ok(_) => {}
-                Err(_) => {}
-            };
+            if let Some(addr) = addr
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+            {
+                let _ = sender.send(addr);
+            }
         });
 
-        thread::sleep(Self::TIMEOUT);
-
-        match receiver.try_recv() {
+        match receiver.recv_timeout(Self::TIMEOUT) {
             Ok(addr) => {
-                let mut stream = TcpStream::connect_timeout(&addr, Self::TIMEOUT)?;
+                resolved_addresses()
+                    .lock()
+                    .unwrap()
+                    .insert(to_addr.to_owned(), addr);
+                Ok(addr)
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("resolving {} timed out", to_addr),
+            )),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(ErrorKind::AddrNotAvailable.into()),
+        }
+    }
+
+    fn connect(to_addr: &str) -> std::io::Result<TcpStream> {
+        let addr = Self::resolve(to_addr)?;
 
-                stream.write(buffer)
+        TcpStream::connect_timeout(&addr, Self::TIMEOUT).inspect_err(|_| {
+            // The name may point somewhere else by now
+            resolved_addresses().lock().unwrap().remove(to_addr);
+        })
+    }
+
+    /// Runs `exchange` on a pooled connection to `to_addr`. A pooled
+    /// connection the peer closed in the meantime is replaced by a fresh one.
+    fn with_connection<T>(
+        to_addr: &str,
+        mut exchange: impl FnMut(&mut TcpStream) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        if let Some(mut stream) = connection_pool().checkout(to_addr) {
+            match exchange(&mut stream) {
+                Ok(result) => {
+                    connection_pool().checkin(to_addr, stream);
+                    return Ok(result);
+                }
+                Err(e) if !is_connection_closed(&e) => return Err(e),
+                Err(_) => {}
             }
-            _ => Err(std::io::ErrorKind::AddrNotAvailable.into()),
         }
+
+        let mut stream = Self::connect(to_addr)?;
+        let result = exchange(&mut stream)?;
+        connection_pool().checkin(to_addr, stream);
+        Ok(result)
     }
 
-    fn process_is_alive(addr: String) -> bool {
-        match Self::send(&addr, &[]) {
-            Ok(_) => true,
-            Err(_) => false,
+    fn send(to_addr: &str, buffer: &[u8], max_frame_size: usize) -> std::io::Result<usize> {
+        Self::with_connection(to_addr, |stream| {
+            write_frame(stream, buffer, max_frame_size)
+        })
+    }
+
+    /// Sends `request` and waits on the same connection for the response
+    /// carrying its correlation id.
+    fn request(
+        to_addr: &str,
+        request: &Envelope,
+        codec: CodecKind,
+        max_frame_size: usize,
+    ) -> std::io::Result<Envelope> {
+        let request_bytes = request.as_bytes_vec(codec);
+        let buffer = Self::with_connection(to_addr, |stream| {
+            write_frame(stream, &request_bytes, max_frame_size)?;
+            stream.set_read_timeout(Some(Self::TIMEOUT))?;
+            Self::receive(stream, max_frame_size)
+        })?;
+        if buffer.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "received an empty response",
+            ));
         }
+
+        let response =
+            Envelope::parse_bytes(&buffer).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if response.correlation_id != request.correlation_id {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "response correlation id {} does not match request {}",
+                    response.correlation_id, request.correlation_id
+                ),
+            ));
+        }
+
+        Ok(response)
+    }
+
+    fn reply(
+        stream: &mut TcpStream,
+        response: &Envelope,
+        codec: CodecKind,
+        max_frame_size: usize,
+    ) -> std::io::Result<usize> {
+        write_frame(stream, &response.as_bytes_vec(codec), max_frame_size)
+    }
+
+    fn receive(stream: &mut TcpStream, max_frame_size: usize) -> std::io::Result<Vec<u8>> {
+        read_frame(stream, max_frame_size)
+    }
+
+    /// Serves every frame a peer sends on `stream` until it disconnects or
+    /// `shutdown` is requested, replying in `codec` with what `handle`
+    /// returns. Frames that don't decode go to `reject`, which may answer
+    /// them in a codec of its own.
+    fn serve_frames(
+        mut stream: TcpStream,
+        shutdown: &Shutdown,
+        logger: &impl Logger,
+        codec: CodecKind,
+        max_frame_size: usize,
+        handle: impl Fn(Envelope) -> Option<Envelope>,
+        reject: impl Fn(&EnvelopeError) -> Option<(Envelope, CodecKind)>,
+    ) {
+        let Ok(peer_addr) = stream.peer_addr() else {
+            return;
+        };
+        let _ = stream.set_nonblocking(false);
+
+        loop {
+            let received = match shutdown.wait_readable(&stream, 2 * IDLE_TIMEOUT) {
+                Ok(true) => Self::receive(&mut stream, max_frame_size),
+                Ok(false) => return,
+                Err(e) => Err(e),
+            };
+            let buffer = match received {
+                Ok(buffer) => buffer,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return,
+                Err(e) => {
+                    logger.log(&format!("Dropping connection from {}: {}", peer_addr, e));
+                    return;
+                }
+            };
+            if buffer.is_empty() {
+                continue;
+            }
+
+            let response = match handle_buffer(&buffer) {
+                Ok(envelope) => handle(envelope).map(|response| (response, codec)),
+                Err(e) => reject(&e).or_else(|| {
+                    logger.log(&format!("Dropping message from {}: {}", peer_addr, e));
+                    None
+                }),
+            };
+            if let Some((response, codec)) = response {
+                if let Err(e) = Self::reply(&mut stream, &response, codec, max_frame_size) {
+                    logger.log(&format!("Couldn't reply to {}: {}", peer_addr, e));
+                }
+            }
+        }
+    }
+
+    /// Probes `addr` over a fresh connection, as a pooled one may outlive
+    /// the peer's listener. The probe is an empty frame, which fits any
+    /// frame size limit.
+    fn process_is_alive(addr: String) -> bool {
+        Self::connect(&addr)
+            .and_then(|mut stream| write_frame(&mut stream, &[], 0))
+            .is_ok()
     }
 }
 
-pub trait Broadcast: P2PSend {
-    fn broadcast_to_all(processes: &HashMap<u32, String>, buffer: &[u8]) -> std::io::Result<usize> {
-        let results: Vec<std::io::Result<usize>> = processes
-            .iter()
-            .map(|(_, addr)| Self::send(&addr, buffer))
-            .collect();
-
-        if results
-            .iter()
-            .filter(|r| r.is_err())
-            .collect::<Vec<_>>()
-            .len()
-            > 0
-        {
-            Err(ErrorKind::Other.into())
-        } else {
-            match results.last().unwrap() {
-                Ok(usize) => Ok(*usize),
-                Err(e) => match e.kind() {
-                    ErrorKind::AddrNotAvailable => Err(ErrorKind::AddrNotAvailable.into()),
-                    _ => Err(ErrorKind::Other.into()),
-                },
+/// Errors meaning the peer closed the connection, as opposed to timing out.
+pub(crate) fn is_connection_closed(error: &Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+pub(crate) fn resolved_addresses() -> &'static Mutex<HashMap<String, SocketAddr>> {
+    static RESOLVED_ADDRESSES: OnceLock<Mutex<HashMap<String, SocketAddr>>> = OnceLock::new();
+    RESOLVED_ADDRESSES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// How long a broadcast waits for peers before giving up on them.
+#[derive(Debug, Clone, Copy)]
+pub enum BroadcastMode {
+    /// Wait for every peer.
+    All,
+    /// Return as soon as this many peers succeeded.
+    Quorum(usize),
+}
+
+pub type BroadcastOutcome<T> = HashMap<u32, std::io::Result<T>>;
+
+pub trait Broadcast: P2PSend + 'static {
+    const BROADCAST_DEADLINE: Duration = Duration::from_secs(10);
+
+    fn broadcast_to_all(
+        processes: &HashMap<u32, String>,
+        buffer: &[u8],
+        max_frame_size: usize,
+    ) -> BroadcastOutcome<usize> {
+        Self::broadcast_with(
+            processes,
+            buffer,
+            max_frame_size,
+            BroadcastMode::All,
+            Self::BROADCAST_DEADLINE,
+        )
+    }
+
+    fn broadcast_with(
+        processes: &HashMap<u32, String>,
+        buffer: &[u8],
+        max_frame_size: usize,
+        mode: BroadcastMode,
+        deadline: Duration,
+    ) -> BroadcastOutcome<usize> {
+        let buffer = buffer.to_vec();
+        fan_out(processes, mode, deadline, move |addr| {
+            Self::send(addr, &buffer, max_frame_size)
+        })
+    }
+
+    fn request_all(
+        processes: &HashMap<u32, String>,
+        request: &Envelope,
+        codec: CodecKind,
+        max_frame_size: usize,
+    ) -> BroadcastOutcome<Envelope> {
+        Self::request_with(
+            processes,
+            request,
+            codec,
+            max_frame_size,
+            BroadcastMode::All,
+            Self::BROADCAST_DEADLINE,
+        )
+    }
+
+    fn request_with(
+        processes: &HashMap<u32, String>,
+        request: &Envelope,
+        codec: CodecKind,
+        max_frame_size: usize,
+        mode: BroadcastMode,
+        deadline: Duration,
+    ) -> BroadcastOutcome<Envelope> {
+        let request = request.clone();
+        fan_out(processes, mode, deadline, move |addr| {
+            Self::request(addr, &request, codec, max_frame_size)
+        })
+    }
+}
+
+/// Runs `exchange` against every peer concurrently and collects each peer's
+/// outcome. Peers still pending when the deadline passes, or once the quorum
+/// is reached, are reported as timed out.
+fn fan_out<T: Send + 'static>(
+    processes: &HashMap<u32, String>,
+    mode: BroadcastMode,
+    deadline: Duration,
+    exchange: impl Fn(&str) -> std::io::Result<T> + Send + Sync + 'static,
+) -> BroadcastOutcome<T> {
+    let exchange = Arc::new(exchange);
+    let (sender, receiver) = mpsc::channel();
+
+    for (&id, addr) in processes {
+        let (exchange, sender, addr) = (exchange.clone(), sender.clone(), addr.clone());
+        thread::spawn(move || {
+            let _ = sender.send((id, exchange(&addr)));
+        });
+    }
+    drop(sender);
+
+    let quorum = match mode {
+        BroadcastMode::All => processes.len(),
+        BroadcastMode::Quorum(quorum) => quorum.min(processes.len()),
+    };
+    let deadline = Instant::now() + deadline;
+    let mut outcomes = HashMap::new();
+    let mut successes = 0;
+
+    while outcomes.len() < processes.len() && successes < quorum {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(remaining) {
+            Ok((id, outcome)) => {
+                if outcome.is_ok() {
+                    successes += 1;
+                }
+                outcomes.insert(id, outcome);
             }
+            Err(_) => break,
         }
     }
+
+    for id in processes.keys() {
+        outcomes.entry(*id).or_insert_with(|| {
+            Err(Error::new(
+                ErrorKind::TimedOut,
+                "no outcome before the broadcast returned",
+            ))
+        });
+    }
+
+    outcomes
 }
 
 pub trait PaxosProposer: Broadcast {
-    fn prepare(seq_number: u32, acceptors: &HashMap<u32, String>) -> std::io::Result<usize> {
-        let request = &PaxosProposerEvent::Prepare { seq_number }.as_bytes_vec()[..];
+    fn prepare(
+        codec: CodecKind,
+        max_frame_size: usize,
+        seq_number: u32,
+        acceptors: &HashMap<u32, String>,
+    ) -> BroadcastOutcome<Envelope> {
+        let request = Envelope::new(None, PaxosProposerEvent::Prepare { seq_number });
 
-        Self::broadcast_to_all(acceptors, request)
+        Self::request_with(
+            acceptors,
+            &request,
+            codec,
+            max_frame_size,
+            BroadcastMode::Quorum(acceptors.len() / 2 + 1),
+            Self::TIMEOUT,
+        )
     }
 
     fn request_accept(
+        codec: CodecKind,
+        max_frame_size: usize,
         seq_number: u32,
         value: PaxosAcceptedValue,
         acceptors: &HashMap<u32, String>,
-    ) -> std::io::Result<usize> {
-        let request = &PaxosProposerEvent::RequestAccept { seq_number, value }.as_bytes_vec()[..];
+    ) -> BroadcastOutcome<Envelope> {
+        let request = Envelope::new(
+            None,
+            PaxosProposerEvent::RequestAccept { seq_number, value },
+        );
 
-        Self::broadcast_to_all(acceptors, request)
+        Self::request_all(acceptors, &request, codec, max_frame_size)
     }
 }
 
+/// Acceptor responses are written back on the connection the proposer's
+/// request came in on.
 pub trait PaxosAcceptor: P2PSend {
     fn promise(
+        from: u32,
+        correlation_id: u64,
         seq_number: u32,
         value: Option<PaxosAcceptedValue>,
-        proposer: String,
-    ) -> std::io::Result<usize> {
-        let request = &PaxosAcceptorEvent::Promise { seq_number, value }.as_bytes_vec()[..];
-
-        Self::send(&proposer, request)
+    ) -> Envelope {
+        Envelope::reply_to(
+            correlation_id,
+            Some(from),
+            PaxosAcceptorEvent::Promise { seq_number, value },
+        )
     }
-    fn no_promise(proposer: String) -> std::io::Result<usize> {
-        let request = &PaxosAcceptorEvent::KO.as_bytes_vec()[..];
 
-        Self::send(&proposer, request)
+    fn no_promise(from: u32, correlation_id: u64) -> Envelope {
+        Envelope::reply_to(correlation_id, Some(from), PaxosAcceptorEvent::KO)
     }
 
     fn respond_accept(
+        from: u32,
+        correlation_id: u64,
         seq_number: u32,
         value: Option<PaxosAcceptedValue>,
-        proposer: String,
-    ) -> std::io::Result<usize> {
-        let request = &PaxosAcceptorEvent::Accepted { seq_number, value }.as_bytes_vec()[..];
-
-        Self::send(&proposer, request)
+    ) -> Envelope {
+        Envelope::reply_to(
+            correlation_id,
+            Some(from),
+            PaxosAcceptorEvent::Accepted { seq_number, value },
+        )
     }
 }
 
@@ -122,14 +423,8 @@ pub trait Logger {
     fn log(&self, msg: &str) {
         let id = self.what_is_id();
         let timestamp = chrono::Local::now().to_string();
-        if id.is_some() {
-            println!(
-                "[{} {} - {}] {}",
-                self.what_is_self(),
-                id.unwrap(),
-                timestamp,
-                msg
-            );
+        if let Some(id) = id {
+            println!("[{} {} - {}] {}", self.what_is_self(), id, timestamp, msg);
         } else {
             println!("[{} - {}] {}", self.what_is_self(), timestamp, msg);
         }
@@ -0,0 +1,444 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use tokio::{
+    net::{lookup_host, TcpStream},
+    task::JoinSet,
+    time::{self, Instant},
+};
+
+use crate::{
+    algorithms::{
+        is_connection_closed, resolved_addresses, BroadcastMode, BroadcastOutcome, Logger,
+        PaxosProposer,
+    },
+    codec::CodecKind,
+    events::{Envelope, EnvelopeError, PaxosAcceptedValue, PaxosProposerEvent},
+    framing::{read_frame_async, write_frame_async},
+    handle_buffer,
+    pool::{connection_pool, IDLE_TIMEOUT},
+    shutdown::Shutdown,
+    Broadcast, P2PSend,
+};
+
+/// Tokio counterpart of [`P2PSend`], sharing its timeouts, resolution cache
+/// and connection pool.
+pub trait AsyncP2PSend: P2PSend {
+    fn resolve_async(to_addr: &str) -> impl Future<Output = std::io::Result<SocketAddr>> + Send {
+        async move {
+            if let Ok(addr) = to_addr.parse() {
+                return Ok(addr);
+            }
+            if let Some(addr) = resolved_addresses().lock().unwrap().get(to_addr) {
+                return Ok(*addr);
+            }
+
+            let addr = time::timeout(Self::TIMEOUT, lookup_host(to_addr))
+                .await
+                .map_err(|_| {
+                    Error::new(
+                        ErrorKind::TimedOut,
+                        format!("resolving {} timed out", to_addr),
+                    )
+                })??
+                .next()
+                .ok_or(ErrorKind::AddrNotAvailable)?;
+
+            resolved_addresses()
+                .lock()
+                .unwrap()
+                .insert(to_addr.to_owned(), addr);
+            Ok(addr)
+        }
+    }
+
+    fn connect_async(to_addr: &str) -> impl Future<Output = std::io::Result<TcpStream>> + Send {
+        async move {
+            let addr = Self::resolve_async(to_addr).await?;
+
+            match time::timeout(Self::TIMEOUT, TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => Ok(stream),
+                result => {
+                    // The name may point somewhere else by now
+                    resolved_addresses().lock().unwrap().remove(to_addr);
+                    result.unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()))
+                }
+            }
+        }
+    }
+
+    /// Writes `payload` as one frame on a pooled connection to `to_addr` and,
+    /// when `response_timeout` is set, waits that long for a frame back. A
+    /// pooled connection the peer closed in the meantime is replaced by a
+    /// fresh one.
+    fn exchange_async(
+        to_addr: &str,
+        payload: &[u8],
+        max_frame_size: usize,
+        response_timeout: Option<Duration>,
+    ) -> impl Future<Output = std::io::Result<Option<Vec<u8>>>> + Send {
+        async move {
+            if let Some(mut stream) = checkout(to_addr) {
+                match Self::round_trip(&mut stream, payload, max_frame_size, response_timeout).await
+                {
+                    Ok(response) => {
+                        checkin(to_addr, stream);
+                        return Ok(response);
+                    }
+                    Err(e) if !is_connection_closed(&e) => return Err(e),
+                    Err(_) => {}
+                }
+            }
+
+            let mut stream = Self::connect_async(to_addr).await?;
+            let response =
+                Self::round_trip(&mut stream, payload, max_frame_size, response_timeout).await?;
+            checkin(to_addr, stream);
+            Ok(response)
+        }
+    }
+
+    fn round_trip<'a>(
+        stream: &'a mut TcpStream,
+        payload: &'a [u8],
+        max_frame_size: usize,
+        response_timeout: Option<Duration>,
+    ) -> impl Future<Output = std::io::Result<Option<Vec<u8>>>> + Send + 'a {
+        async move {
+            write_frame_async(stream, payload, max_frame_size).await?;
+
+            match response_timeout {
+                Some(response_timeout) => time::timeout(
+                    response_timeout,
+                    Self::receive_async(stream, max_frame_size),
+                )
+                .await
+                .map_err(|_| Error::from(ErrorKind::TimedOut))?
+                .map(Some),
+                None => Ok(None),
+            }
+        }
+    }
+
+    fn send_async(
+        to_addr: &str,
+        buffer: &[u8],
+        max_frame_size: usize,
+    ) -> impl Future<Output = std::io::Result<usize>> + Send {
+        async move {
+            Self::exchange_async(to_addr, buffer, max_frame_size, None).await?;
+            Ok(buffer.len())
+        }
+    }
+
+    /// Sends `request` and waits on the same connection for the response
+    /// carrying its correlation id.
+    fn request_async(
+        to_addr: &str,
+        request: &Envelope,
+        codec: CodecKind,
+        max_frame_size: usize,
+    ) -> impl Future<Output = std::io::Result<Envelope>> + Send {
+        let request_bytes = request.as_bytes_vec(codec);
+        let correlation_id = request.correlation_id;
+
+        async move {
+            let buffer =
+                Self::exchange_async(to_addr, &request_bytes, max_frame_size, Some(Self::TIMEOUT))
+                    .await?
+                    .unwrap_or_default();
+            if buffer.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "received an empty response",
+                ));
+            }
+
+            let response = Envelope::parse_bytes(&buffer)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            if response.correlation_id != correlation_id {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "response correlation id {} does not match request {}",
+                        response.correlation_id, correlation_id
+                    ),
+                ));
+            }
+
+            Ok(response)
+        }
+    }
+
+    fn reply_async<'a>(
+        stream: &'a mut TcpStream,
+        response: &Envelope,
+        codec: CodecKind,
+        max_frame_size: usize,
+    ) -> impl Future<Output = std::io::Result<usize>> + Send + 'a {
+        let response_bytes = response.as_bytes_vec(codec);
+
+        async move { write_frame_async(stream, &response_bytes, max_frame_size).await }
+    }
+
+    fn receive_async(
+        stream: &mut TcpStream,
+        max_frame_size: usize,
+    ) -> impl Future<Output = std::io::Result<Vec<u8>>> + Send + '_ {
+        read_frame_async(stream, max_frame_size)
+    }
+
+    /// Same as [`P2PSend::serve_frames`], with `handle` awaited on the current
+    /// tokio runtime.
+    fn serve_frames_async<Response>(
+        mut stream: TcpStream,
+        shutdown: &Shutdown,
+        logger: &(impl Logger + Sync),
+        codec: CodecKind,
+        max_frame_size: usize,
+        handle: impl Fn(Envelope) -> Response + Send,
+        reject: impl Fn(&EnvelopeError) -> Option<(Envelope, CodecKind)> + Send,
+    ) -> impl Future<Output = ()> + Send
+    where
+        Response: Future<Output = Option<Envelope>> + Send,
+    {
+        async move {
+            let Ok(peer_addr) = stream.peer_addr() else {
+                return;
+            };
+
+            loop {
+                let received = match shutdown
+                    .wait_readable_async(&stream, 2 * IDLE_TIMEOUT)
+                    .await
+                {
+                    Ok(true) => time::timeout(
+                        2 * IDLE_TIMEOUT,
+                        Self::receive_async(&mut stream, max_frame_size),
+                    )
+                    .await
+                    .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into())),
+                    Ok(false) => return,
+                    Err(e) => Err(e),
+                };
+                let buffer = match received {
+                    Ok(buffer) => buffer,
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => return,
+                    Err(e) => {
+                        logger.log(&format!("Dropping connection from {}: {}", peer_addr, e));
+                        return;
+                    }
+                };
+                if buffer.is_empty() {
+                    continue;
+                }
+
+                let response = match handle_buffer(&buffer) {
+                    Ok(envelope) => handle(envelope).await.map(|response| (response, codec)),
+                    Err(e) => reject(&e).or_else(|| {
+                        logger.log(&format!("Dropping message from {}: {}", peer_addr, e));
+                        None
+                    }),
+                };
+                if let Some((response, codec)) = response {
+                    if let Err(e) =
+                        Self::reply_async(&mut stream, &response, codec, max_frame_size).await
+                    {
+                        logger.log(&format!("Couldn't reply to {}: {}", peer_addr, e));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Same as [`P2PSend::process_is_alive`].
+    fn process_is_alive_async(addr: String) -> impl Future<Output = bool> + Send {
+        async move {
+            match Self::connect_async(&addr).await {
+                Ok(mut stream) => write_frame_async(&mut stream, &[], 0).await.is_ok(),
+                Err(_) => false,
+            }
+        }
+    }
+}
+
+/// Pooled connections are kept as blocking std streams so both runtimes can
+/// share them.
+fn checkout(addr: &str) -> Option<TcpStream> {
+    let stream = connection_pool().checkout(addr)?;
+    stream.set_nonblocking(true).ok()?;
+    TcpStream::from_std(stream).ok()
+}
+
+fn checkin(addr: &str, stream: TcpStream) {
+    if let Ok(stream) = stream.into_std() {
+        if stream.set_nonblocking(false).is_ok() {
+            connection_pool().checkin(addr, stream);
+        }
+    }
+}
+
+/// Tokio counterpart of [`Broadcast`], with one task per peer instead of one
+/// thread.
+pub trait AsyncBroadcast: AsyncP2PSend + Broadcast {
+    fn broadcast_to_all_async(
+        processes: &HashMap<u32, String>,
+        buffer: &[u8],
+        max_frame_size: usize,
+    ) -> impl Future<Output = BroadcastOutcome<usize>> + Send {
+        Self::broadcast_with_async(
+            processes,
+            buffer,
+            max_frame_size,
+            BroadcastMode::All,
+            Self::BROADCAST_DEADLINE,
+        )
+    }
+
+    fn broadcast_with_async(
+        processes: &HashMap<u32, String>,
+        buffer: &[u8],
+        max_frame_size: usize,
+        mode: BroadcastMode,
+        deadline: Duration,
+    ) -> impl Future<Output = BroadcastOutcome<usize>> + Send {
+        let buffer = buffer.to_vec();
+        fan_out(processes, mode, deadline, move |addr| {
+            let buffer = buffer.clone();
+            async move { Self::send_async(&addr, &buffer, max_frame_size).await }
+        })
+    }
+
+    fn request_all_async(
+        processes: &HashMap<u32, String>,
+        request: &Envelope,
+        codec: CodecKind,
+        max_frame_size: usize,
+    ) -> impl Future<Output = BroadcastOutcome<Envelope>> + Send {
+        Self::request_with_async(
+            processes,
+            request,
+            codec,
+            max_frame_size,
+            BroadcastMode::All,
+            Self::BROADCAST_DEADLINE,
+        )
+    }
+
+    fn request_with_async(
+        processes: &HashMap<u32, String>,
+        request: &Envelope,
+        codec: CodecKind,
+        max_frame_size: usize,
+        mode: BroadcastMode,
+        deadline: Duration,
+    ) -> impl Future<Output = BroadcastOutcome<Envelope>> + Send {
+        let request = request.clone();
+        fan_out(processes, mode, deadline, move |addr| {
+            let request = request.clone();
+            async move { Self::request_async(&addr, &request, codec, max_frame_size).await }
+        })
+    }
+}
+
+/// Runs `exchange` against every peer in its own task and collects each
+/// peer's outcome. Peers still pending when the deadline passes, or once the
+/// quorum is reached, are reported as timed out and left to finish in the
+/// background.
+fn fan_out<T, F>(
+    processes: &HashMap<u32, String>,
+    mode: BroadcastMode,
+    deadline: Duration,
+    exchange: impl Fn(String) -> F,
+) -> impl Future<Output = BroadcastOutcome<T>> + Send
+where
+    T: Send + 'static,
+    F: Future<Output = std::io::Result<T>> + Send + 'static,
+{
+    let mut exchanges = JoinSet::new();
+    for (&id, addr) in processes {
+        let exchange = exchange(addr.clone());
+        exchanges.spawn(async move { (id, exchange.await) });
+    }
+
+    let ids: Vec<u32> = processes.keys().copied().collect();
+    let quorum = match mode {
+        BroadcastMode::All => ids.len(),
+        BroadcastMode::Quorum(quorum) => quorum.min(ids.len()),
+    };
+    let deadline = Instant::now() + deadline;
+
+    async move {
+        let mut outcomes = HashMap::new();
+        let mut successes = 0;
+
+        while successes < quorum {
+            match time::timeout_at(deadline, exchanges.join_next()).await {
+                Ok(Some(Ok((id, outcome)))) => {
+                    if outcome.is_ok() {
+                        successes += 1;
+                    }
+                    outcomes.insert(id, outcome);
+                }
+                // A panicked task is reported as pending below
+                Ok(Some(Err(_))) => {}
+                Ok(None) | Err(_) => break,
+            }
+        }
+        exchanges.detach_all();
+
+        for id in ids {
+            outcomes.entry(id).or_insert_with(|| {
+                Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "no outcome before the broadcast returned",
+                ))
+            });
+        }
+
+        outcomes
+    }
+}
+
+pub trait AsyncPaxosProposer: AsyncBroadcast + PaxosProposer {
+    fn prepare_async(
+        codec: CodecKind,
+        max_frame_size: usize,
+        seq_number: u32,
+        acceptors: &HashMap<u32, String>,
+    ) -> impl Future<Output = BroadcastOutcome<Envelope>> + Send {
+        let request = Envelope::new(None, PaxosProposerEvent::Prepare { seq_number });
+
+        async move {
+            Self::request_with_async(
+                acceptors,
+                &request,
+                codec,
+                max_frame_size,
+                BroadcastMode::Quorum(acceptors.len() / 2 + 1),
+                Self::TIMEOUT,
+            )
+            .await
+        }
+    }
+
+    fn request_accept_async(
+        codec: CodecKind,
+        max_frame_size: usize,
+        seq_number: u32,
+        value: PaxosAcceptedValue,
+        acceptors: &HashMap<u32, String>,
+    ) -> impl Future<Output = BroadcastOutcome<Envelope>> + Send {
+        let request = Envelope::new(
+            None,
+            PaxosProposerEvent::RequestAccept { seq_number, value },
+        );
+
+        async move { Self::request_all_async(acceptors, &request, codec, max_frame_size).await }
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+/// A registry node and the other nodes of its cluster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterConfig {
+    /// Unique among the nodes of the cluster.
+    pub node_id: u32,
+    /// Address of every other node, by node id.
+    pub peers: HashMap<u32, String>,
+    /// Followers that haven't heard from a leader for this long, plus up to
+    /// half as long again at random, start an election.
+    pub election_timeout: Duration,
+    /// How often the leader replicates to followers, even without changes.
+    pub heartbeat_interval: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Election state of a node, as in Raft: each node votes at most once per
+/// term, and only for candidates whose last change isn't older than its own
+/// by term, then revision. A term has at most one leader, and as the leader
+/// only acknowledges a registration once a majority holds it, every later
+/// leader holds it too.
+#[derive(Debug)]
+pub struct Election {
+    term: u64,
+    role: Role,
+    voted_for: Option<u32>,
+    leader: Option<u32>,
+    election_timeout: Duration,
+    election_deadline: Instant,
+}
+
+impl ClusterConfig {
+    pub fn new(node_id: u32, peers: HashMap<u32, String>) -> Self {
+        ClusterConfig {
+            node_id,
+            peers,
+            election_timeout: Duration::from_millis(1500),
+            heartbeat_interval: Duration::from_millis(300),
+        }
+    }
+
+    /// Votes a candidate needs to win, its own included.
+    pub fn quorum(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+}
+
+impl Election {
+    pub fn new(election_timeout: Duration) -> Self {
+        let mut election = Election {
+            term: 0,
+            role: Role::Follower,
+            voted_for: None,
+            leader: None,
+            election_timeout,
+            election_deadline: Instant::now(),
+        };
+        election.reset_deadline();
+        election
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn voted_for(&self) -> Option<u32> {
+        self.voted_for
+    }
+
+    pub fn leader(&self) -> Option<u32> {
+        self.leader
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    /// Resumes from the term and vote a node had before it restarted.
+    pub fn restore(&mut self, term: u64, voted_for: Option<u32>) {
+        self.term = term;
+        self.voted_for = voted_for;
+    }
+
+    pub fn election_is_due(&self) -> bool {
+        self.role != Role::Leader && Instant::now() >= self.election_deadline
+    }
+
+    /// Moves to the next term as a candidate voting for itself, and returns
+    /// that term.
+    pub fn start_election(&mut self, node_id: u32) -> u64 {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(node_id);
+        self.leader = None;
+        self.reset_deadline();
+        self.term
+    }
+
+    /// Steps down to follower if `term` is newer than the current one.
+    /// Returns whether it was.
+    pub fn observe_term(&mut self, term: u64) -> bool {
+        if term <= self.term {
+            return false;
+        }
+        self.term = term;
+        self.role = Role::Follower;
+        self.voted_for = None;
+        self.leader = None;
+        true
+    }
+
+    /// Follows `leader`, heard from in the current term.
+    pub fn follow(&mut self, leader: u32) {
+        self.role = Role::Follower;
+        self.leader = Some(leader);
+        self.reset_deadline();
+    }
+
+    /// Votes for `candidate` in the current term, unless the node already
+    /// voted for another one or has a more recent last change.
+    pub fn vote(&mut self, term: u64, candidate: u32, candidate_is_up_to_date: bool) -> bool {
+        let granted = term == self.term
+            && candidate_is_up_to_date
+            && self
+                .voted_for
+                .is_none_or(|voted_for| voted_for == candidate);
+        if granted {
+            self.voted_for = Some(candidate);
+            self.reset_deadline();
+        }
+        granted
+    }
+
+    /// Takes the lead if the node is still a candidate in `term`.
+    pub fn win(&mut self, node_id: u32, term: u64) -> bool {
+        if self.role != Role::Candidate || self.term != term {
+            return false;
+        }
+        self.role = Role::Leader;
+        self.leader = Some(node_id);
+        true
+    }
+
+    /// Randomized so nodes rarely start competing elections at once.
+    fn reset_deadline(&mut self) {
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=self.election_timeout / 2);
+        self.election_deadline = Instant::now() + self.election_timeout + jitter;
+    }
+}
@@ -0,0 +1,104 @@
+use std::fmt;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Serialization format used for envelopes. Every encoded message starts with
+/// the id of the codec that produced it, so nodes using different codecs can
+/// still talk to each other.
+pub trait Codec {
+    fn id(&self) -> u8;
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+#[derive(Debug)]
+pub struct CodecError(String);
+
+pub struct JsonCodec;
+
+/// Compact binary encoding, noticeably smaller than JSON for large
+/// membership tables.
+pub struct MessagePackCodec;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CodecKind {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl Codec for JsonCodec {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(value).map_err(|e| CodecError(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(bytes).map_err(|e| CodecError(e.to_string()))
+    }
+}
+
+impl Codec for MessagePackCodec {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        // Structs are written as maps so `#[serde(default)]` fields stay optional
+        rmp_serde::to_vec_named(value).map_err(|e| CodecError(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        rmp_serde::from_slice(bytes).map_err(|e| CodecError(e.to_string()))
+    }
+}
+
+impl CodecKind {
+    pub fn from_id(id: u8) -> Option<Self> {
+        [CodecKind::Json, CodecKind::MessagePack]
+            .into_iter()
+            .find(|codec| codec.id() == id)
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "json" => Some(CodecKind::Json),
+            "msgpack" | "messagepack" => Some(CodecKind::MessagePack),
+            _ => None,
+        }
+    }
+}
+
+impl Codec for CodecKind {
+    fn id(&self) -> u8 {
+        match self {
+            CodecKind::Json => JsonCodec.id(),
+            CodecKind::MessagePack => MessagePackCodec.id(),
+        }
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            CodecKind::Json => JsonCodec.encode(value),
+            CodecKind::MessagePack => MessagePackCodec.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        match self {
+            CodecKind::Json => JsonCodec.decode(bytes),
+            CodecKind::MessagePack => MessagePackCodec.decode(bytes),
+        }
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
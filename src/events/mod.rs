@@ -1,36 +1,251 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    codec::{Codec, CodecError, CodecKind},
+    service::{MembershipDelta, ServiceInstance, ServiceQuery},
+};
+
+/// Version of the wire envelope, bumped on incompatible changes.
+///
+/// Version 2 replaced the bare `id -> "ip:port"` tables with service
+/// instances, version 3 stamps every registry event with the revision of the
+/// table it was built from, version 4 adds registry clusters.
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// Every protocol version this build can still speak, oldest first.
+pub const SUPPORTED_PROTOCOL_VERSIONS: [u16; 1] = [4];
+
+/// Optional features a node advertises during the connect handshake.
+pub const CAPABILITIES: [Capability; 1] = [Capability::Paxos];
+
+/// Message kinds this build knows how to decode, as written in the `kind` field.
+const KNOWN_KINDS: [&str; 5] = [
+    "ProcessEvent",
+    "RegistryEvent",
+    "PaxosAcceptorEvent",
+    "PaxosProposerEvent",
+    "ClusterEvent",
+];
+
+static NEXT_CORRELATION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Everything sent between nodes travels in an envelope, which is decoded in
+/// a single pass regardless of the event it carries. On the wire the envelope
+/// is prefixed with the id of the codec used to encode it.
+///
+/// The codec id, `version`, `correlation_id` and the `kind`/`payload` layout
+/// of the event stay the same in every protocol version, and so does
+/// `RegistryEvent::Rejected`, so peers can always tell each other which
+/// versions they speak.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    pub version: u16,
+    pub sender: Option<u32>,
+    pub correlation_id: u64,
+    pub event: Event,
+}
+
+#[derive(Debug)]
+pub enum EnvelopeError {
+    /// `correlation_id` and `codec` are the peer's, to reject its request
+    /// with.
+    UnsupportedVersion {
+        version: u16,
+        correlation_id: u64,
+        codec: CodecKind,
+    },
+    UnknownKind(String),
+    UnknownCodec(u8),
+    Malformed(CodecError),
+}
+
+/// Just enough of an envelope to explain why a full decode failed.
+#[derive(Deserialize)]
+struct EnvelopeHeader {
+    version: u16,
+    #[serde(default)]
+    correlation_id: u64,
+    event: EventHeader,
+}
+
+#[derive(Deserialize)]
+struct EventHeader {
+    kind: String,
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", content = "payload")]
 pub enum Event {
     ProcessEvent(ProcessEvent),
     RegistryEvent(RegistryEvent),
     PaxosAcceptorEvent(PaxosAcceptorEvent),
     PaxosProposerEvent(PaxosProposerEvent),
+    ClusterEvent(ClusterEvent),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Paxos,
+    /// A capability introduced by a newer build.
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ProcessEvent {
-    ConnectOnPort { port: u32 },
-    Message { from: u32, msg: String },
+    ConnectOnPort {
+        port: u32,
+        protocol_versions: Vec<u16>,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+        /// Description of the service, the registry fills in the rest.
+        #[serde(default)]
+        instance: Box<ServiceInstance>,
+    },
+    Message {
+        from: u32,
+        msg: String,
+    },
+    /// Sent by a process leaving cleanly. Only accepted from the address the
+    /// instance registered from, or with its `instance_uuid`, as followers
+    /// forward it to the leader from their own address.
+    Deregister {
+        id: u32,
+        #[serde(default)]
+        instance_uuid: Option<String>,
+    },
+    /// Request answered with `RegistryEvent::LeaseRenewed`, or
+    /// `RegistryEvent::LeaseExpired` once the registry dropped the process.
+    RenewLease {
+        id: u32,
+    },
+    /// Request answered with `RegistryEvent::LookupResult`.
+    Lookup {
+        query: ServiceQuery,
+    },
+    /// Subscribes the listener on `port` to changes of `service_name`, or of
+    /// every service. Watchers resuming after missed updates pass the last
+    /// revision they saw as `since`, the others get a snapshot first.
+    Watch {
+        service_name: Option<String>,
+        port: u32,
+        since: Option<u64>,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Every registry event carries the revision of the membership table it was
+/// built from, the registry bumps it on each change to the table.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum RegistryEvent {
     Registered {
         given_id: u32,
-        registered_processes: HashMap<u32, String>,
+        registered_processes: HashMap<u32, ServiceInstance>,
+        revision: u64,
+        protocol_version: u16,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
+    Rejected {
+        reason: String,
+        supported_versions: Vec<u16>,
+        revision: u64,
+    },
+    UpdateRegisteredProcesses {
+        revision: u64,
+        processes: HashMap<u32, ServiceInstance>,
+    },
+    LookupResult {
+        revision: u64,
+        instances: Vec<ServiceInstance>,
+    },
+    LeaseRenewed {
+        revision: u64,
+        ttl: Duration,
+    },
+    LeaseExpired {
+        revision: u64,
+    },
+    /// Pushed to watchers, `deltas` take them from revision `since` to
+    /// `revision`.
+    MembershipDeltas {
+        since: u64,
+        revision: u64,
+        deltas: Vec<MembershipDelta>,
+    },
+    /// Pushed to new watchers, and to those too far behind to catch up from
+    /// deltas.
+    MembershipSnapshot {
+        revision: u64,
+        instances: HashMap<u32, ServiceInstance>,
+    },
+    /// Answers writes sent to a follower of a registry cluster, `leader` is
+    /// the address of the leader if the follower knows it.
+    NotLeader {
+        revision: u64,
+        leader: Option<String>,
+    },
+}
+
+/// Sent between the nodes of a registry cluster.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ClusterEvent {
+    /// Request answered with `Vote`, `revision` is the candidate's and
+    /// `last_term` the term of the change that produced it.
+    RequestVote {
+        term: u64,
+        candidate_id: u32,
+        revision: u64,
+        last_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    /// Request answered with `Replicated`. Carries the deltas taking a
+    /// follower at revision `since`, produced by a change in `since_term`,
+    /// to the leader's table, none at all when it's only a heartbeat.
+    AppendChanges {
+        term: u64,
+        leader_id: u32,
+        since: u64,
+        since_term: u64,
+        last_registered_id: u32,
+        deltas: Vec<MembershipDelta>,
+    },
+    /// Request answered with `Replicated`, replaces the follower's table.
+    InstallSnapshot {
+        term: u64,
+        leader_id: u32,
+        revision: u64,
+        revision_term: u64,
+        last_registered_id: u32,
+        processes: HashMap<u32, ServiceInstance>,
+    },
+    /// `revision` is the follower's once it handled the request, which it
+    /// refuses if `term` is stale or its table isn't the one at `since` in
+    /// `since_term`.
+    Replicated {
+        term: u64,
+        revision: u64,
+        success: bool,
     },
-    UpdateRegisteredProcesses(HashMap<u32, String>),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PaxosAcceptedValue {
     pub seq_number: u32,
     pub value: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum PaxosProposerEvent {
     Prepare {
         seq_number: u32,
@@ -41,7 +256,7 @@ pub enum PaxosProposerEvent {
     },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum PaxosAcceptorEvent {
     Promise {
         seq_number: u32,
@@ -62,105 +277,149 @@ pub enum PaxosStatus {
     ConsensusReached(PaxosAcceptedValue),
 }
 
-impl Event {
-    pub fn parse_event_type(bytes: &[u8]) -> Option<Event> {
-        match ProcessEvent::parse_bytes(bytes) {
-            Some(process_event) => Some(Event::ProcessEvent(process_event)),
-            None => match RegistryEvent::parse_bytes(bytes) {
-                Some(registry_event) => Some(Event::RegistryEvent(registry_event)),
-                None => match PaxosAcceptorEvent::parse_bytes(bytes) {
-                    Some(paxos_acceptor_event) => {
-                        Some(Event::PaxosAcceptorEvent(paxos_acceptor_event))
-                    }
-                    None => match PaxosProposerEvent::parse_bytes(bytes) {
-                        Some(paxos_proposer_event) => {
-                            Some(Event::PaxosProposerEvent(paxos_proposer_event))
-                        }
-                        None => None,
-                    },
-                },
-            },
-        }
+impl ProcessEvent {
+    /// Writes change the registry's table, a cluster only takes them on its
+    /// leader.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            ProcessEvent::ConnectOnPort { .. }
+                | ProcessEvent::Deregister { .. }
+                | ProcessEvent::RenewLease { .. }
+        )
     }
 }
 
-impl RegistryEvent {
-    pub fn as_bytes_vec(self) -> Vec<u8> {
-        self.serialize()
+impl Envelope {
+    pub fn new(sender: Option<u32>, event: impl Into<Event>) -> Self {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            sender,
+            correlation_id: NEXT_CORRELATION_ID.fetch_add(1, Ordering::Relaxed),
+            event: event.into(),
+        }
     }
 
-    pub fn parse_bytes(bytes: &[u8]) -> Option<Self> {
-        match Self::deserialize(bytes.to_vec()) {
-            Ok(event) => Some(event),
-            Err(_) => None,
+    /// Builds the response to the request identified by `correlation_id`.
+    pub fn reply_to(correlation_id: u64, sender: Option<u32>, event: impl Into<Event>) -> Self {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            sender,
+            correlation_id,
+            event: event.into(),
         }
     }
 
-    fn serialize(&self) -> Vec<u8> {
-        serde_json::to_vec(self).unwrap()
+    pub fn as_bytes_vec(&self, codec: CodecKind) -> Vec<u8> {
+        let mut bytes = vec![codec.id()];
+        bytes.extend(codec.encode(self).unwrap());
+        bytes
     }
 
-    fn deserialize(bytes: Vec<u8>) -> Result<Self, serde_json::Error> {
-        serde_json::from_slice(&bytes[..])
-    }
-}
+    pub fn parse_bytes(bytes: &[u8]) -> Result<Self, EnvelopeError> {
+        let (&codec_id, bytes) = bytes.split_first().ok_or(EnvelopeError::UnknownCodec(0))?;
+        let codec = CodecKind::from_id(codec_id).ok_or(EnvelopeError::UnknownCodec(codec_id))?;
 
-impl ProcessEvent {
-    pub fn as_bytes_vec(self) -> Vec<u8> {
-        self.serialize()
-    }
-    pub fn parse_bytes(bytes: &[u8]) -> Option<Self> {
-        match Self::deserialize(bytes.to_vec()) {
-            Ok(event) => Some(event),
-            Err(_) => None,
+        let envelope: Envelope = codec
+            .decode(bytes)
+            .map_err(|e| EnvelopeError::classify(codec, bytes, e))?;
+
+        let is_rejection = matches!(
+            envelope.event,
+            Event::RegistryEvent(RegistryEvent::Rejected { .. })
+        );
+        if !SUPPORTED_PROTOCOL_VERSIONS.contains(&envelope.version) && !is_rejection {
+            return Err(EnvelopeError::UnsupportedVersion {
+                version: envelope.version,
+                correlation_id: envelope.correlation_id,
+                codec,
+            });
         }
-    }
 
-    fn serialize(&self) -> Vec<u8> {
-        serde_json::to_vec(self).unwrap()
+        Ok(envelope)
     }
+}
 
-    fn deserialize(bytes: Vec<u8>) -> Result<Self, serde_json::Error> {
-        serde_json::from_slice(&bytes[..])
-    }
+/// Picks the highest protocol version offered by a peer that this build also
+/// supports.
+pub fn negotiate_version(offered: &[u16]) -> Option<u16> {
+    offered
+        .iter()
+        .filter(|version| SUPPORTED_PROTOCOL_VERSIONS.contains(version))
+        .max()
+        .copied()
 }
 
-impl PaxosAcceptorEvent {
-    pub fn as_bytes_vec(self) -> Vec<u8> {
-        self.serialize()
-    }
-    pub fn parse_bytes(bytes: &[u8]) -> Option<Self> {
-        match Self::deserialize(bytes.to_vec()) {
-            Ok(event) => Some(event),
-            Err(_) => None,
+/// Capabilities both sides of a handshake have in common.
+pub fn common_capabilities(offered: &[Capability]) -> Vec<Capability> {
+    CAPABILITIES
+        .iter()
+        .filter(|capability| offered.contains(capability))
+        .copied()
+        .collect()
+}
+
+impl EnvelopeError {
+    fn classify(codec: CodecKind, bytes: &[u8], error: CodecError) -> Self {
+        match codec.decode::<EnvelopeHeader>(bytes) {
+            Ok(header) if !SUPPORTED_PROTOCOL_VERSIONS.contains(&header.version) => {
+                EnvelopeError::UnsupportedVersion {
+                    version: header.version,
+                    correlation_id: header.correlation_id,
+                    codec,
+                }
+            }
+            Ok(header) if !KNOWN_KINDS.contains(&header.event.kind.as_str()) => {
+                EnvelopeError::UnknownKind(header.event.kind)
+            }
+            _ => EnvelopeError::Malformed(error),
         }
     }
+}
 
-    fn serialize(&self) -> Vec<u8> {
-        serde_json::to_vec(self).unwrap()
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::UnsupportedVersion { version, .. } => write!(
+                f,
+                "unsupported protocol version {} (supported: {:?})",
+                version, SUPPORTED_PROTOCOL_VERSIONS
+            ),
+            EnvelopeError::UnknownKind(kind) => write!(f, "unknown message kind {:?}", kind),
+            EnvelopeError::UnknownCodec(id) => write!(f, "unknown codec id {}", id),
+            EnvelopeError::Malformed(e) => write!(f, "malformed envelope: {}", e),
+        }
     }
+}
+
+impl std::error::Error for EnvelopeError {}
 
-    fn deserialize(bytes: Vec<u8>) -> Result<Self, serde_json::Error> {
-        serde_json::from_slice(&bytes[..])
+impl From<ProcessEvent> for Event {
+    fn from(event: ProcessEvent) -> Self {
+        Event::ProcessEvent(event)
     }
 }
 
-impl PaxosProposerEvent {
-    pub fn as_bytes_vec(self) -> Vec<u8> {
-        self.serialize()
+impl From<RegistryEvent> for Event {
+    fn from(event: RegistryEvent) -> Self {
+        Event::RegistryEvent(event)
     }
-    pub fn parse_bytes(bytes: &[u8]) -> Option<Self> {
-        match Self::deserialize(bytes.to_vec()) {
-            Ok(event) => Some(event),
-            Err(_) => None,
-        }
+}
+
+impl From<PaxosAcceptorEvent> for Event {
+    fn from(event: PaxosAcceptorEvent) -> Self {
+        Event::PaxosAcceptorEvent(event)
     }
+}
 
-    fn serialize(&self) -> Vec<u8> {
-        serde_json::to_vec(self).unwrap()
+impl From<PaxosProposerEvent> for Event {
+    fn from(event: PaxosProposerEvent) -> Self {
+        Event::PaxosProposerEvent(event)
     }
+}
 
-    fn deserialize(bytes: Vec<u8>) -> Result<Self, serde_json::Error> {
-        serde_json::from_slice(&bytes[..])
+impl From<ClusterEvent> for Event {
+    fn from(event: ClusterEvent) -> Self {
+        Event::ClusterEvent(event)
     }
 }
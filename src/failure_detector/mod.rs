@@ -0,0 +1,196 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How suspicious a failure detector is of a peer, and what to do about it.
+#[derive(Debug, Clone, Copy)]
+pub struct FailureDetectorConfig {
+    /// Peers are suspected from this phi on, but kept.
+    pub suspect_threshold: f64,
+    /// Peers are declared dead from this phi on.
+    pub dead_threshold: f64,
+    /// Inter-arrival times the distribution is estimated from.
+    pub max_samples: usize,
+    /// Floor on the standard deviation, so peers answering like clockwork
+    /// aren't declared dead the moment one answer is late.
+    pub min_std_deviation: Duration,
+    /// Failed probes are retried this many times within a sweep, so a
+    /// single lost connection doesn't count as a missed heartbeat.
+    pub probe_retries: u32,
+    /// How long a peer stays suspect before it may be declared dead.
+    pub suspect_grace_period: Duration,
+}
+
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub enum Suspicion {
+    #[default]
+    Alive,
+    Suspect,
+    Dead,
+}
+
+/// Phi accrual failure detector for a single peer, as described by Hayashibara
+/// et al. Heartbeat inter-arrival times are assumed normally distributed, phi
+/// is `-log10` of the probability that a heartbeat arrives even later than
+/// the time elapsed since the last one.
+#[derive(Debug, Clone)]
+pub struct PhiAccrual {
+    intervals: VecDeque<f64>,
+    last_heartbeat: Instant,
+    max_samples: usize,
+    min_std_deviation: f64,
+}
+
+impl Default for FailureDetectorConfig {
+    fn default() -> Self {
+        FailureDetectorConfig {
+            suspect_threshold: 3.0,
+            dead_threshold: 8.0,
+            max_samples: 100,
+            min_std_deviation: Duration::from_millis(500),
+            probe_retries: 2,
+            suspect_grace_period: Duration::from_secs(15),
+        }
+    }
+}
+
+impl FailureDetectorConfig {
+    pub fn assess(&self, phi: f64) -> Suspicion {
+        if phi >= self.dead_threshold {
+            Suspicion::Dead
+        } else if phi >= self.suspect_threshold {
+            Suspicion::Suspect
+        } else {
+            Suspicion::Alive
+        }
+    }
+}
+
+impl PhiAccrual {
+    /// Starts tracking a peer that was just heard from and is expected to
+    /// be heard from every `expected_interval`.
+    pub fn new(expected_interval: Duration, config: &FailureDetectorConfig) -> Self {
+        // Seed the history with a guess, a quarter of the interval either way
+        let expected_interval = millis(expected_interval);
+        let deviation = expected_interval / 4.0;
+
+        PhiAccrual {
+            intervals: VecDeque::from([
+                expected_interval - deviation,
+                expected_interval + deviation,
+            ]),
+            last_heartbeat: Instant::now(),
+            max_samples: config.max_samples.max(2),
+            min_std_deviation: millis(config.min_std_deviation),
+        }
+    }
+
+    pub fn heartbeat(&mut self) {
+        let now = Instant::now();
+        self.intervals.push_back(millis(now - self.last_heartbeat));
+        if self.intervals.len() > self.max_samples {
+            self.intervals.pop_front();
+        }
+        self.last_heartbeat = now;
+    }
+
+    /// Suspicion level of the peer right now.
+    pub fn phi(&self) -> f64 {
+        self.phi_after(self.last_heartbeat.elapsed())
+    }
+
+    /// Suspicion level of the peer once `elapsed` passed since its last
+    /// heartbeat.
+    fn phi_after(&self, elapsed: Duration) -> f64 {
+        let elapsed = millis(elapsed);
+        let samples = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<f64>() / samples;
+        let variance = self
+            .intervals
+            .iter()
+            .map(|interval| (interval - mean).powi(2))
+            .sum::<f64>()
+            / samples;
+        let std_deviation = variance.sqrt().max(self.min_std_deviation);
+
+        // Logistic approximation of the normal CDF
+        let y = (elapsed - mean) / std_deviation;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        let p_later = if elapsed > mean {
+            e / (1.0 + e)
+        } else {
+            1.0 - 1.0 / (1.0 + e)
+        };
+
+        -p_later.max(f64::MIN_POSITIVE).log10()
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A detector expecting a heartbeat every second, whose deviation is
+    /// floored at half a second.
+    fn detector() -> PhiAccrual {
+        PhiAccrual::new(Duration::from_secs(1), &FailureDetectorConfig::default())
+    }
+
+    #[test]
+    fn phi_stays_low_before_the_mean_interval() {
+        let detector = detector();
+
+        assert!(detector.phi_after(Duration::ZERO) < 0.05);
+        assert!(detector.phi_after(Duration::from_millis(500)) < 0.1);
+        assert!(detector.phi_after(Duration::from_millis(1000)) < 0.35);
+    }
+
+    #[test]
+    fn phi_at_two_deviations_past_the_mean() {
+        // The tail past two deviations holds about 2.3% of heartbeats
+        let phi = detector().phi_after(Duration::from_millis(2000));
+
+        assert!((1.55..1.75).contains(&phi), "phi {}", phi);
+    }
+
+    #[test]
+    fn phi_grows_with_the_silence() {
+        let detector = detector();
+        let phis: Vec<f64> = [500, 1000, 1500, 2000, 3000, 5000]
+            .into_iter()
+            .map(|millis| detector.phi_after(Duration::from_millis(millis)))
+            .collect();
+
+        assert!(phis.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", phis);
+    }
+
+    #[test]
+    fn phi_far_past_the_mean_is_finite_and_dead() {
+        let config = FailureDetectorConfig::default();
+        let phi = detector().phi_after(Duration::from_secs(60));
+
+        assert!(phi.is_finite());
+        assert_eq!(config.assess(phi), Suspicion::Dead);
+    }
+
+    #[test]
+    fn assess_uses_inclusive_thresholds() {
+        let config = FailureDetectorConfig::default();
+
+        assert_eq!(config.assess(0.0), Suspicion::Alive);
+        assert_eq!(config.assess(2.99), Suspicion::Alive);
+        assert_eq!(config.assess(3.0), Suspicion::Suspect);
+        assert_eq!(config.assess(7.99), Suspicion::Suspect);
+        assert_eq!(config.assess(8.0), Suspicion::Dead);
+        assert_eq!(config.assess(f64::INFINITY), Suspicion::Dead);
+    }
+}
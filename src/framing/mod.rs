@@ -0,0 +1,104 @@
+use std::io::{Error, ErrorKind, Read, Write};
+
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Size of the big-endian length prefix written before every frame.
+pub const FRAME_HEADER_SIZE: usize = 4;
+
+/// Default upper bound for a single frame, large enough for membership tables
+/// with thousands of entries.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Writes `payload` as a single length-prefixed frame.
+///
+/// Returns the number of payload bytes written, not counting the header.
+pub fn write_frame<W: Write>(
+    writer: &mut W,
+    payload: &[u8],
+    max_frame_size: usize,
+) -> std::io::Result<usize> {
+    let frame = encode_frame(payload, max_frame_size)?;
+
+    writer.write_all(&frame)?;
+    writer.flush()?;
+
+    Ok(payload.len())
+}
+
+/// Reads one length-prefixed frame.
+///
+/// A peer closing the connection yields an `UnexpectedEof` error, while an
+/// empty frame (e.g. a liveness probe) yields an empty payload. Frames
+/// announcing more than `max_frame_size` bytes are rejected before any of the
+/// payload is read.
+pub fn read_frame<R: Read>(reader: &mut R, max_frame_size: usize) -> std::io::Result<Vec<u8>> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+
+    let mut payload = vec![0u8; frame_size(header, max_frame_size)?];
+    reader.read_exact(&mut payload)?;
+
+    Ok(payload)
+}
+
+/// Async counterpart of [`write_frame`].
+#[cfg(feature = "async")]
+pub async fn write_frame_async<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    payload: &[u8],
+    max_frame_size: usize,
+) -> std::io::Result<usize> {
+    let frame = encode_frame(payload, max_frame_size)?;
+
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+
+    Ok(payload.len())
+}
+
+/// Async counterpart of [`read_frame`].
+#[cfg(feature = "async")]
+pub async fn read_frame_async<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_frame_size: usize,
+) -> std::io::Result<Vec<u8>> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+
+    let mut payload = vec![0u8; frame_size(header, max_frame_size)?];
+    reader.read_exact(&mut payload).await?;
+
+    Ok(payload)
+}
+
+fn encode_frame(payload: &[u8], max_frame_size: usize) -> std::io::Result<Vec<u8>> {
+    if payload.len() > max_frame_size || payload.len() > u32::MAX as usize {
+        return Err(frame_too_large(payload.len(), max_frame_size));
+    }
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+
+    Ok(frame)
+}
+
+fn frame_size(header: [u8; FRAME_HEADER_SIZE], max_frame_size: usize) -> std::io::Result<usize> {
+    let frame_size = u32::from_be_bytes(header) as usize;
+    if frame_size > max_frame_size {
+        return Err(frame_too_large(frame_size, max_frame_size));
+    }
+
+    Ok(frame_size)
+}
+
+fn frame_too_large(frame_size: usize, max_frame_size: usize) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!(
+            "frame of {} bytes exceeds the maximum frame size of {} bytes",
+            frame_size, max_frame_size
+        ),
+    )
+}
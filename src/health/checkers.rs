@@ -0,0 +1,89 @@
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::HealthStatus;
+use crate::{service::ServiceInstance, shutdown::POLL_INTERVAL};
+
+pub fn tcp(instance: &ServiceInstance, timeout: Duration) -> HealthStatus {
+    match connect(&instance.address, instance.port, timeout) {
+        Some(_) => HealthStatus::Passing,
+        None => HealthStatus::Critical,
+    }
+}
+
+pub fn http(instance: &ServiceInstance, path: &str, port: u32, timeout: Duration) -> HealthStatus {
+    let status_code = connect(&instance.address, port, timeout).and_then(|mut stream| {
+        stream.set_read_timeout(Some(timeout)).ok()?;
+        stream.set_write_timeout(Some(timeout)).ok()?;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\n\r\n",
+            path, instance.address, port
+        );
+        stream.write_all(request.as_bytes()).ok()?;
+
+        // Only the status line matters, e.g. "HTTP/1.1 200 OK"
+        let mut response = [0; 64];
+        let read = stream.read(&mut response).ok()?;
+        let status_line = String::from_utf8_lossy(&response[..read]);
+        status_line.split_whitespace().nth(1)?.parse::<u16>().ok()
+    });
+
+    match status_code {
+        Some(200..=299) => HealthStatus::Passing,
+        Some(429) => HealthStatus::Warning,
+        _ => HealthStatus::Critical,
+    }
+}
+
+pub fn command(
+    instance: &ServiceInstance,
+    program: &str,
+    args: &[String],
+    timeout: Duration,
+) -> HealthStatus {
+    let child = Command::new(program)
+        .args(args)
+        .env("INSTANCE_ADDRESS", &instance.address)
+        .env("INSTANCE_PORT", instance.port.to_string())
+        .env("INSTANCE_ID", instance.instance_id.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(_) => return HealthStatus::Critical,
+    };
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(exit_status)) => {
+                return match exit_status.code() {
+                    Some(0) => HealthStatus::Passing,
+                    Some(1) => HealthStatus::Warning,
+                    _ => HealthStatus::Critical,
+                }
+            }
+            Ok(None) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL / 4),
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return HealthStatus::Critical;
+            }
+        }
+    }
+}
+
+fn connect(address: &str, port: u32, timeout: Duration) -> Option<TcpStream> {
+    let addr: SocketAddr = (address, u16::try_from(port).ok()?)
+        .to_socket_addrs()
+        .ok()?
+        .next()?;
+    TcpStream::connect_timeout(&addr, timeout).ok()
+}
@@ -0,0 +1,293 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::service::ServiceInstance;
+
+mod checkers;
+
+/// Checks are never run more often than this.
+pub const MIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest timeout a check gets unless the [`HealthCheckPolicy`] says
+/// otherwise.
+pub const DEFAULT_MAX_CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Health of an instance as last assessed by the registry, ordered from best
+/// to worst.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    #[default]
+    Passing,
+    Warning,
+    Critical,
+}
+
+/// How the registry probes an instance.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum CheckKind {
+    /// Passing if a TCP connection to the instance can be opened.
+    Tcp,
+    /// GETs `path` from the instance, on `port` if set. A 2xx response is
+    /// passing, 429 is warning and anything else critical.
+    Http { path: String, port: Option<u32> },
+    /// Runs `program` on the registry host, with the instance's address in
+    /// `INSTANCE_ADDRESS`, `INSTANCE_PORT` and `INSTANCE_ID`. Exiting with 0
+    /// is passing, 1 is warning and anything else critical. Only taken by
+    /// registries whose [`HealthCheckPolicy`] allows `program`.
+    Command { program: String, args: Vec<String> },
+}
+
+/// Which checks a registry agrees to run. Any client may register, so
+/// command checks, which run programs on the registry host, are refused
+/// unless their program was explicitly allowed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheckPolicy {
+    /// Programs command checks may run, compared with the `program` of the
+    /// check as is. Checks pass them any arguments, so only allow programs
+    /// that are safe to run with arguments chosen by a client. Empty, the
+    /// default, disables command checks.
+    pub allowed_commands: Vec<String>,
+    /// Checks asking for a longer timeout are given this one, as a running
+    /// check holds a worker until it gives up.
+    pub max_check_timeout: Duration,
+}
+
+/// A check an instance asks the registry to run against it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    pub kind: CheckKind,
+    #[serde(default = "default_interval")]
+    pub interval: Duration,
+    #[serde(default = "default_timeout")]
+    pub timeout: Duration,
+    /// Consecutive passing runs before the instance is passing again.
+    #[serde(default = "one")]
+    pub successes_before_passing: u32,
+    /// Consecutive failed runs before the instance is at least warning.
+    #[serde(default = "one")]
+    pub failures_before_warning: u32,
+    /// Consecutive failed runs before a critical result makes the instance
+    /// critical.
+    #[serde(default = "default_failures_before_critical")]
+    pub failures_before_critical: u32,
+}
+
+/// Where a scheduled check stands.
+#[derive(Debug, Clone)]
+pub struct CheckState {
+    pub check: HealthCheck,
+    next_run: Instant,
+    in_flight: bool,
+    successes: u32,
+    failures: u32,
+}
+
+impl HealthCheck {
+    pub fn new(kind: CheckKind) -> Self {
+        HealthCheck {
+            kind,
+            interval: default_interval(),
+            timeout: default_timeout(),
+            successes_before_passing: one(),
+            failures_before_warning: one(),
+            failures_before_critical: default_failures_before_critical(),
+        }
+    }
+
+    /// Runs the check once against `instance`, giving up after `timeout`.
+    pub fn run(&self, instance: &ServiceInstance) -> HealthStatus {
+        match &self.kind {
+            CheckKind::Tcp => checkers::tcp(instance, self.timeout),
+            CheckKind::Http { path, port } => {
+                checkers::http(instance, path, port.unwrap_or(instance.port), self.timeout)
+            }
+            CheckKind::Command { program, args } => {
+                checkers::command(instance, program, args, self.timeout)
+            }
+        }
+    }
+}
+
+impl Default for HealthCheckPolicy {
+    fn default() -> Self {
+        HealthCheckPolicy {
+            allowed_commands: vec![],
+            max_check_timeout: DEFAULT_MAX_CHECK_TIMEOUT,
+        }
+    }
+}
+
+impl HealthCheckPolicy {
+    /// `check` as the registry runs it, no more often than
+    /// [`MIN_CHECK_INTERVAL`] and for no longer than `max_check_timeout`.
+    pub fn bound(&self, check: HealthCheck) -> HealthCheck {
+        HealthCheck {
+            interval: check.interval.max(MIN_CHECK_INTERVAL),
+            timeout: check.timeout.min(self.max_check_timeout),
+            ..check
+        }
+    }
+
+    /// Why the registry refuses to run `check`, if it does.
+    pub fn refusal(&self, check: &HealthCheck) -> Option<String> {
+        match &check.kind {
+            CheckKind::Tcp => None,
+            // The path goes straight into the request line
+            CheckKind::Http { path, .. }
+                if path.chars().any(|c| c.is_ascii_control() || c == ' ') =>
+            {
+                Some("HTTP check path contains spaces or control characters".to_owned())
+            }
+            CheckKind::Http { .. } => None,
+            CheckKind::Command { .. } if self.allowed_commands.is_empty() => {
+                Some("command checks are disabled on this registry".to_owned())
+            }
+            CheckKind::Command { program, .. } if !self.allowed_commands.contains(program) => Some(
+                format!("command {:?} isn't allowed on this registry", program),
+            ),
+            CheckKind::Command { .. } => None,
+        }
+    }
+}
+
+impl CheckState {
+    pub fn new(check: HealthCheck) -> Self {
+        CheckState {
+            check,
+            next_run: Instant::now(),
+            in_flight: false,
+            successes: 0,
+            failures: 0,
+        }
+    }
+
+    /// Whether the check should run now, marking it as running if so.
+    pub fn start_if_due(&mut self, now: Instant) -> bool {
+        if self.in_flight || self.next_run > now {
+            return false;
+        }
+        self.in_flight = true;
+        true
+    }
+
+    /// Records the outcome of a run and returns the instance's new status,
+    /// starting from `status`.
+    pub fn record(&mut self, result: HealthStatus, status: HealthStatus) -> HealthStatus {
+        self.in_flight = false;
+        self.next_run = Instant::now() + self.check.interval;
+
+        if result == HealthStatus::Passing {
+            self.successes += 1;
+            self.failures = 0;
+            return if self.successes >= self.check.successes_before_passing {
+                HealthStatus::Passing
+            } else {
+                status
+            };
+        }
+
+        self.successes = 0;
+        self.failures += 1;
+        if self.failures >= self.check.failures_before_critical {
+            result
+        } else if self.failures >= self.check.failures_before_warning {
+            status.max(HealthStatus::Warning).min(result)
+        } else {
+            status
+        }
+    }
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_failures_before_critical() -> u32 {
+    3
+}
+
+fn one() -> u32 {
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_state(successes_before_passing: u32) -> CheckState {
+        CheckState::new(HealthCheck {
+            successes_before_passing,
+            failures_before_warning: 1,
+            failures_before_critical: 3,
+            ..HealthCheck::new(CheckKind::Tcp)
+        })
+    }
+
+    /// Statuses after recording each of `results` in turn, from `status`.
+    fn record_all(
+        state: &mut CheckState,
+        mut status: HealthStatus,
+        results: &[HealthStatus],
+    ) -> Vec<HealthStatus> {
+        results
+            .iter()
+            .map(|result| {
+                status = state.record(*result, status);
+                status
+            })
+            .collect()
+    }
+
+    #[test]
+    fn becomes_unhealthy_once_failures_reach_each_threshold() {
+        use HealthStatus::*;
+
+        let mut state = check_state(1);
+        assert_eq!(
+            record_all(&mut state, Passing, &[Critical, Critical, Critical]),
+            [Warning, Warning, Critical]
+        );
+
+        // A warning result never makes the instance critical
+        let mut state = check_state(1);
+        assert_eq!(
+            record_all(&mut state, Passing, &[Warning, Warning, Warning, Warning]),
+            [Warning, Warning, Warning, Warning]
+        );
+    }
+
+    #[test]
+    fn becomes_healthy_after_enough_consecutive_successes() {
+        use HealthStatus::*;
+
+        let mut state = check_state(2);
+        assert_eq!(
+            record_all(&mut state, Critical, &[Passing, Critical, Passing, Passing]),
+            [Critical, Critical, Critical, Passing]
+        );
+    }
+
+    #[test]
+    fn bounds_interval_and_timeout() {
+        let policy = HealthCheckPolicy {
+            max_check_timeout: Duration::from_secs(10),
+            ..HealthCheckPolicy::default()
+        };
+        let check = policy.bound(HealthCheck {
+            interval: Duration::ZERO,
+            timeout: Duration::from_secs(3600),
+            ..HealthCheck::new(CheckKind::Tcp)
+        });
+
+        assert_eq!(check.interval, MIN_CHECK_INTERVAL);
+        assert_eq!(check.timeout, Duration::from_secs(10));
+    }
+}
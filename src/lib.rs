@@ -1,22 +1,240 @@
 mod algorithms;
+#[cfg(feature = "async")]
+mod async_algorithms;
+mod cluster;
+mod codec;
 mod events;
+mod failure_detector;
+mod framing;
+mod health;
+mod pool;
 mod process;
 mod registry;
+mod service;
+mod shutdown;
+mod storage;
+mod watch;
+
+use std::collections::HashMap;
+#[cfg(feature = "async")]
+use std::sync::Arc;
 
 use algorithms::{Broadcast, P2PSend};
-use events::Event;
+pub use cluster::ClusterConfig;
+pub use codec::CodecKind;
+use events::{Envelope, EnvelopeError};
+pub use failure_detector::{FailureDetectorConfig, Suspicion};
+pub use framing::DEFAULT_MAX_FRAME_SIZE;
+pub use health::{CheckKind, HealthCheck, HealthCheckPolicy, HealthStatus};
 use process::Process;
+pub use process::{ConnectionState, ReconnectPolicy};
 use registry::Registry;
+pub use service::{ServiceInstance, ServiceQuery};
+pub use shutdown::ShutdownHandle;
+pub use storage::{FsyncPolicy, PersistenceConfig};
+pub use watch::Watch;
 
-pub fn start_registry(addr: String) -> std::io::Result<()> {
-    Registry::new().run(&addr)
+pub fn start_registry(
+    addr: String,
+    codec: CodecKind,
+    max_frame_size: usize,
+    failure_detector: FailureDetectorConfig,
+    persistence: Option<PersistenceConfig>,
+    cluster: Option<ClusterConfig>,
+    health_check_policy: HealthCheckPolicy,
+) -> std::io::Result<()> {
+    Registry::new(
+        codec,
+        max_frame_size,
+        failure_detector,
+        persistence,
+        cluster,
+        health_check_policy,
+    )
+    .run(&addr)
 }
 
-pub fn start_process(port: u32, registry_address: String) -> std::io::Result<()> {
-    let process = Process::new(port, registry_address.clone())?;
+/// Runs a process registered with the first of `registry_addresses` that
+/// answers, moving on to the next one whenever it stops answering. Once none
+/// does, the process registers again as `reconnect` says.
+pub fn start_process(
+    port: u32,
+    registry_addresses: Vec<String>,
+    codec: CodecKind,
+    max_frame_size: usize,
+    instance: ServiceInstance,
+    failure_detector: FailureDetectorConfig,
+    reconnect: ReconnectPolicy,
+) -> std::io::Result<()> {
+    let process = Process::new(
+        port,
+        registry_addresses,
+        codec,
+        max_frame_size,
+        instance,
+        failure_detector,
+        reconnect,
+    )?;
     process.run()
 }
 
-fn handle_buffer(buffer: [u8; 1000], data_size: usize) -> Option<Event> {
-    Event::parse_event_type(&buffer[..data_size])
+/// Starts a registry in the background, stopped through the returned handle.
+pub fn spawn_registry(
+    addr: &str,
+    codec: CodecKind,
+    max_frame_size: usize,
+    failure_detector: FailureDetectorConfig,
+    persistence: Option<PersistenceConfig>,
+    cluster: Option<ClusterConfig>,
+    health_check_policy: HealthCheckPolicy,
+) -> std::io::Result<ShutdownHandle> {
+    Registry::new(
+        codec,
+        max_frame_size,
+        failure_detector,
+        persistence,
+        cluster,
+        health_check_policy,
+    )
+    .start(addr)
+}
+
+/// Starts a process in the background, stopped through the returned handle.
+pub fn spawn_process(
+    port: u32,
+    registry_addresses: Vec<String>,
+    codec: CodecKind,
+    max_frame_size: usize,
+    instance: ServiceInstance,
+    failure_detector: FailureDetectorConfig,
+    reconnect: ReconnectPolicy,
+) -> std::io::Result<ShutdownHandle> {
+    Process::new(
+        port,
+        registry_addresses,
+        codec,
+        max_frame_size,
+        instance,
+        failure_detector,
+        reconnect,
+    )?
+    .start()
+}
+
+/// Asks the registry at `registry_address` for the instances matching
+/// `query`, without registering.
+pub fn lookup(
+    registry_address: &str,
+    query: ServiceQuery,
+    codec: CodecKind,
+    max_frame_size: usize,
+) -> std::io::Result<Vec<ServiceInstance>> {
+    Process::lookup(registry_address, query, codec, max_frame_size)
+}
+
+/// Watches the registry at `registry_address` for changes to the instances
+/// of `service_name`, or of every service, which it pushes to `port`.
+/// `on_change` is called with the watched instances after each one. Stopped
+/// through the returned handle.
+pub fn watch(
+    registry_address: &str,
+    service_name: Option<&str>,
+    port: u32,
+    codec: CodecKind,
+    max_frame_size: usize,
+    on_change: impl Fn(&HashMap<u32, ServiceInstance>) + Send + Sync + 'static,
+) -> std::io::Result<ShutdownHandle> {
+    Watch::new(
+        registry_address,
+        service_name,
+        port,
+        codec,
+        max_frame_size,
+        on_change,
+    )
+    .start()
+}
+
+/// Same as [`start_registry`], on the current tokio runtime.
+#[cfg(feature = "async")]
+pub async fn start_registry_async(
+    addr: String,
+    codec: CodecKind,
+    max_frame_size: usize,
+    failure_detector: FailureDetectorConfig,
+    persistence: Option<PersistenceConfig>,
+    cluster: Option<ClusterConfig>,
+    health_check_policy: HealthCheckPolicy,
+) -> std::io::Result<()> {
+    Arc::new(Registry::new(
+        codec,
+        max_frame_size,
+        failure_detector,
+        persistence,
+        cluster,
+        health_check_policy,
+    ))
+    .run_async(&addr)
+    .await
+}
+
+/// Same as [`start_process`], on the current tokio runtime.
+#[cfg(feature = "async")]
+pub async fn start_process_async(
+    port: u32,
+    registry_addresses: Vec<String>,
+    codec: CodecKind,
+    max_frame_size: usize,
+    instance: ServiceInstance,
+    failure_detector: FailureDetectorConfig,
+    reconnect: ReconnectPolicy,
+) -> std::io::Result<()> {
+    let process = Process::new(
+        port,
+        registry_addresses,
+        codec,
+        max_frame_size,
+        instance,
+        failure_detector,
+        reconnect,
+    )?;
+    Arc::new(process).run_async().await
+}
+
+/// Same as [`watch`], on the current tokio runtime, running until the
+/// watch fails.
+#[cfg(feature = "async")]
+pub async fn watch_async(
+    registry_address: &str,
+    service_name: Option<&str>,
+    port: u32,
+    codec: CodecKind,
+    max_frame_size: usize,
+    on_change: impl Fn(&HashMap<u32, ServiceInstance>) + Send + Sync + 'static,
+) -> std::io::Result<()> {
+    Arc::new(Watch::new(
+        registry_address,
+        service_name,
+        port,
+        codec,
+        max_frame_size,
+        on_change,
+    ))
+    .run_async()
+    .await
+}
+
+/// Same as [`lookup`], on the current tokio runtime.
+#[cfg(feature = "async")]
+pub async fn lookup_async(
+    registry_address: &str,
+    query: ServiceQuery,
+    codec: CodecKind,
+    max_frame_size: usize,
+) -> std::io::Result<Vec<ServiceInstance>> {
+    Process::lookup_async(registry_address, query, codec, max_frame_size).await
+}
+
+fn handle_buffer(buffer: &[u8]) -> Result<Envelope, EnvelopeError> {
+    Envelope::parse_bytes(buffer)
 }
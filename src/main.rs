@@ -1,26 +1,98 @@
 use std::env;
 use std::io::ErrorKind;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
 
+#[cfg(not(feature = "async"))]
 use processes::start_process;
+#[cfg(not(feature = "async"))]
 use processes::start_registry;
+use processes::{
+    CheckKind, ClusterConfig, FailureDetectorConfig, FsyncPolicy, HealthCheck, HealthCheckPolicy,
+    PersistenceConfig, ReconnectPolicy, ServiceInstance,
+};
+use processes::{CodecKind, DEFAULT_MAX_FRAME_SIZE};
+
+#[cfg(feature = "async")]
+fn start_registry(
+    addr: String,
+    codec: CodecKind,
+    max_frame_size: usize,
+    failure_detector: FailureDetectorConfig,
+    persistence: Option<PersistenceConfig>,
+    cluster: Option<ClusterConfig>,
+    health_check_policy: HealthCheckPolicy,
+) -> std::io::Result<()> {
+    tokio::runtime::Runtime::new()?.block_on(processes::start_registry_async(
+        addr,
+        codec,
+        max_frame_size,
+        failure_detector,
+        persistence,
+        cluster,
+        health_check_policy,
+    ))
+}
+
+#[cfg(feature = "async")]
+fn start_process(
+    port: u32,
+    registry_addresses: Vec<String>,
+    codec: CodecKind,
+    max_frame_size: usize,
+    instance: ServiceInstance,
+    failure_detector: FailureDetectorConfig,
+    reconnect: ReconnectPolicy,
+) -> std::io::Result<()> {
+    tokio::runtime::Runtime::new()?.block_on(processes::start_process_async(
+        port,
+        registry_addresses,
+        codec,
+        max_frame_size,
+        instance,
+        failure_detector,
+        reconnect,
+    ))
+}
 
 fn main() {
     let mut port = 8080;
-    let mut registry_addr = format!("0.0.0.0:{port}");
+    let mut registry_addr =
+        env::var("REGISTRY_LISTEN_ADDR").unwrap_or_else(|_| format!("0.0.0.0:{port}"));
     let mut is_registry = true;
 
-    match env::var("REGISTRY_ADDR") {
-        Ok(addr) => {
-            registry_addr = addr;
-            is_registry = false;
-        }
-        Err(_) => {}
+    if let Ok(addr) = env::var("REGISTRY_ADDR") {
+        registry_addr = addr;
+        is_registry = false;
+    }
+
+    let codec = match env::var("CODEC") {
+        Ok(name) => CodecKind::from_name(&name).unwrap_or_else(|| panic!("Unknown codec {}", name)),
+        Err(_) => CodecKind::default(),
     };
+    // Frames over `MAX_FRAME_SIZE` bytes are neither sent nor read
+    let max_frame_size = parsed_var("MAX_FRAME_SIZE").unwrap_or(DEFAULT_MAX_FRAME_SIZE);
+    let instance = service_instance();
+    let failure_detector = failure_detector_config();
+    let reconnect = reconnect_policy();
 
     // Start registry
-    if is_registry == true {
-        match start_registry(registry_addr.clone()) {
+    if is_registry {
+        match start_registry(
+            registry_addr.clone(),
+            codec,
+            max_frame_size,
+            failure_detector,
+            persistence_config(),
+            cluster_config(),
+            health_check_policy(),
+        ) {
             Ok(_) => {}
+            Err(e) if e.kind() != ErrorKind::AddrInUse => {
+                eprintln!("Couldn't run registry on {}: {}", registry_addr, e);
+                process::exit(1);
+            }
             Err(_) => {
                 println!(
                     "Starting regular process, registry located at {}",
@@ -28,17 +100,25 @@ fn main() {
                 );
 
                 // If the registry is already started, start a regular process
-                while match start_process(port, registry_addr.clone()) {
+                while match start_process(
+                    port,
+                    vec![registry_addr.clone()],
+                    codec,
+                    max_frame_size,
+                    instance.clone(),
+                    failure_detector,
+                    reconnect.clone(),
+                ) {
                     Ok(_) => false,
                     Err(e) => match e.kind() {
                         ErrorKind::AddrInUse => {
-                            port = port + 1;
+                            port += 1;
                             println!("Trying with port {}", port);
-                            start_process(port, registry_addr.clone());
                             true
                         }
                         _ => {
-                            panic!("Unknown error {}", e);
+                            eprintln!("Process stopped: {}", e);
+                            process::exit(1);
                         }
                     },
                 } {}
@@ -50,19 +130,211 @@ fn main() {
             registry_addr
         );
         // If the registry is already started, start a regular process
-        while match start_process(port, registry_addr.clone()) {
+        while match start_process(
+            port,
+            registry_addresses(&registry_addr),
+            codec,
+            max_frame_size,
+            instance.clone(),
+            failure_detector,
+            reconnect.clone(),
+        ) {
             Ok(_) => false,
             Err(e) => match e.kind() {
                 ErrorKind::AddrInUse => {
-                    port = port + 1;
+                    port += 1;
                     println!("Trying with {}", port);
-                    start_process(port, registry_addr.clone());
                     true
                 }
                 _ => {
-                    panic!("Unknown error {}", e);
+                    eprintln!("Process stopped: {}", e);
+                    process::exit(1);
                 }
             },
         } {}
     }
 }
+
+/// Registries listed in `REGISTRY_ADDR`, comma separated, which the process
+/// tries in turn.
+fn registry_addresses(addrs: &str) -> Vec<String> {
+    addrs
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Describes the service this process provides from `SERVICE_NAME`,
+/// `SERVICE_VERSION`, `SERVICE_TAGS` (comma separated) and `SERVICE_METADATA`
+/// (comma separated `key=value` pairs). Setting `LEASE_TTL` (seconds) makes the
+/// process renew a lease instead of being probed by the registry.
+///
+/// `HEALTH_CHECK` asks the registry to check the process, with `tcp`,
+/// `http:<path>` or `command:<program> <args>...`, every
+/// `HEALTH_CHECK_INTERVAL` seconds with a `HEALTH_CHECK_TIMEOUT`.
+fn service_instance() -> ServiceInstance {
+    let mut instance = ServiceInstance::new(
+        env::var("SERVICE_NAME").unwrap_or_else(|_| "process".to_owned()),
+        env::var("SERVICE_VERSION").unwrap_or_else(|_| env!("CARGO_PKG_VERSION").to_owned()),
+    );
+
+    if let Ok(tags) = env::var("SERVICE_TAGS") {
+        instance.tags = tags
+            .split(',')
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_owned)
+            .collect();
+    }
+    if let Ok(metadata) = env::var("SERVICE_METADATA") {
+        instance.metadata = metadata
+            .split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect();
+    }
+    instance.lease_ttl = seconds_var("LEASE_TTL");
+    if let Ok(spec) = env::var("HEALTH_CHECK") {
+        let mut check = HealthCheck::new(check_kind(&spec));
+        if let Some(interval) = seconds_var("HEALTH_CHECK_INTERVAL") {
+            check.interval = interval;
+        }
+        if let Some(timeout) = seconds_var("HEALTH_CHECK_TIMEOUT") {
+            check.timeout = timeout;
+        }
+        instance.health_check = Some(check);
+    }
+
+    instance
+}
+
+/// Failure detector thresholds from `PHI_SUSPECT_THRESHOLD` and
+/// `PHI_DEAD_THRESHOLD`, with `PROBE_RETRIES` retries of a failed probe and
+/// processes staying suspect for `SUSPECT_GRACE_PERIOD` seconds before
+/// they're removed.
+fn failure_detector_config() -> FailureDetectorConfig {
+    let mut config = FailureDetectorConfig::default();
+    if let Some(threshold) = parsed_var("PHI_SUSPECT_THRESHOLD") {
+        config.suspect_threshold = threshold;
+    }
+    if let Some(threshold) = parsed_var("PHI_DEAD_THRESHOLD") {
+        config.dead_threshold = threshold;
+    }
+    if let Some(retries) = parsed_var("PROBE_RETRIES") {
+        config.probe_retries = retries;
+    }
+    if let Some(grace_period) = seconds_var("SUSPECT_GRACE_PERIOD") {
+        config.suspect_grace_period = grace_period;
+    }
+    config
+}
+
+/// Retries registering with a backoff starting at `RECONNECT_BACKOFF_MS`
+/// milliseconds and capped at `RECONNECT_MAX_BACKOFF` seconds, up to
+/// `RECONNECT_MAX_RETRIES` times, or forever if that's `forever`.
+fn reconnect_policy() -> ReconnectPolicy {
+    let mut policy = ReconnectPolicy::default();
+    if let Some(backoff) = parsed_var("RECONNECT_BACKOFF_MS") {
+        policy.initial_backoff = Duration::from_millis(backoff);
+    }
+    if let Some(backoff) = seconds_var("RECONNECT_MAX_BACKOFF") {
+        policy.max_backoff = backoff;
+    }
+    match env::var("RECONNECT_MAX_RETRIES").as_deref() {
+        Ok("forever") => policy.max_retries = None,
+        Ok(_) => policy.max_retries = parsed_var("RECONNECT_MAX_RETRIES"),
+        Err(_) => {}
+    }
+    policy
+}
+
+/// Keeps the registry's state in `REGISTRY_DATA_DIR` if set, syncing it to
+/// disk as `REGISTRY_FSYNC` says: `always` (the default), `never`, or every
+/// that many milliseconds. Without it a restarted registry gives out ids from
+/// 1 again.
+fn persistence_config() -> Option<PersistenceConfig> {
+    let data_dir = env::var("REGISTRY_DATA_DIR").ok()?;
+    let fsync = match env::var("REGISTRY_FSYNC") {
+        Ok(name) => {
+            FsyncPolicy::from_name(&name).unwrap_or_else(|| panic!("Unknown fsync policy {}", name))
+        }
+        Err(_) => FsyncPolicy::Always,
+    };
+
+    Some(PersistenceConfig {
+        data_dir: data_dir.into(),
+        fsync,
+    })
+}
+
+/// Lets command health checks run the programs listed in
+/// `HEALTH_CHECK_COMMANDS`, comma separated. Without it they're refused.
+/// Checks time out after `HEALTH_CHECK_MAX_TIMEOUT` seconds at most.
+fn health_check_policy() -> HealthCheckPolicy {
+    let default = HealthCheckPolicy::default();
+    HealthCheckPolicy {
+        allowed_commands: env::var("HEALTH_CHECK_COMMANDS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|program| !program.is_empty())
+            .map(str::to_owned)
+            .collect(),
+        max_check_timeout: seconds_var("HEALTH_CHECK_MAX_TIMEOUT")
+            .unwrap_or(default.max_check_timeout),
+    }
+}
+
+/// Makes the registry node `REGISTRY_NODE_ID` of a cluster whose other nodes
+/// are listed in `REGISTRY_PEERS` as comma separated `id=address` pairs. The
+/// node listens on `REGISTRY_LISTEN_ADDR` if set.
+fn cluster_config() -> Option<ClusterConfig> {
+    let node_id = parsed_var("REGISTRY_NODE_ID")?;
+    let peers = env::var("REGISTRY_PEERS")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|peer| !peer.is_empty())
+        .map(|peer| {
+            let (id, addr) = peer
+                .split_once('=')
+                .unwrap_or_else(|| panic!("Invalid registry peer {}", peer));
+            let id = id
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid registry peer id {}", id));
+            (id, addr.to_owned())
+        })
+        .collect();
+
+    Some(ClusterConfig::new(node_id, peers))
+}
+
+fn check_kind(spec: &str) -> CheckKind {
+    match spec.split_once(':') {
+        None if spec == "tcp" => CheckKind::Tcp,
+        Some(("http", path)) => CheckKind::Http {
+            path: path.to_owned(),
+            port: None,
+        },
+        Some(("command", command)) => {
+            let mut words = command.split_whitespace().map(str::to_owned);
+            CheckKind::Command {
+                program: words.next().expect("Missing health check command"),
+                args: words.collect(),
+            }
+        }
+        _ => panic!("Unknown health check {}", spec),
+    }
+}
+
+fn seconds_var(name: &str) -> Option<Duration> {
+    parsed_var(name).map(Duration::from_secs)
+}
+
+fn parsed_var<T: FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    let value = value
+        .parse()
+        .unwrap_or_else(|_| panic!("Invalid {} {}", name, value));
+    Some(value)
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    net::TcpStream,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// Connections unused for longer than this are closed instead of reused.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Upper bound of idle connections kept per peer.
+pub const MAX_IDLE_PER_PEER: usize = 4;
+
+struct PooledConnection {
+    stream: TcpStream,
+    last_used: Instant,
+}
+
+/// Keeps established connections to peers, keyed by the address found in the
+/// membership tables, so consecutive messages to the same peer reuse them.
+pub struct ConnectionPool {
+    connections: Mutex<HashMap<String, Vec<PooledConnection>>>,
+}
+
+impl ConnectionPool {
+    fn new() -> Self {
+        ConnectionPool {
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a healthy idle connection to `addr` out of the pool, closing any
+    /// stale one found along the way.
+    pub fn checkout(&self, addr: &str) -> Option<TcpStream> {
+        let connections = &mut *self.connections.lock().unwrap();
+        let idle = connections.get_mut(addr)?;
+
+        while let Some(connection) = idle.pop() {
+            if connection.last_used.elapsed() < IDLE_TIMEOUT && is_healthy(&connection.stream) {
+                return Some(connection.stream);
+            }
+        }
+        connections.remove(addr);
+        None
+    }
+
+    /// Hands a connection back once the caller is done with it.
+    pub fn checkin(&self, addr: &str, stream: TcpStream) {
+        let connections = &mut *self.connections.lock().unwrap();
+        let idle = connections.entry(addr.to_owned()).or_default();
+
+        if idle.len() < MAX_IDLE_PER_PEER {
+            idle.push(PooledConnection {
+                stream,
+                last_used: Instant::now(),
+            });
+        }
+    }
+
+    pub fn evict_idle(&self) {
+        let connections = &mut *self.connections.lock().unwrap();
+        connections.values_mut().for_each(|idle| {
+            idle.retain(|connection| connection.last_used.elapsed() < IDLE_TIMEOUT)
+        });
+        connections.retain(|_, idle| !idle.is_empty());
+    }
+
+    /// Drops connections to the peers at `addrs`, once they left.
+    pub fn remove_peers(&self, addrs: impl IntoIterator<Item = String>) {
+        let connections = &mut *self.connections.lock().unwrap();
+        addrs.into_iter().for_each(|addr| {
+            connections.remove(&addr);
+        });
+    }
+
+    /// Drops connections to peers that left the membership table.
+    pub fn retain_peers<'a>(&self, addrs: impl IntoIterator<Item = &'a String>) {
+        let addrs: HashSet<&String> = addrs.into_iter().collect();
+        self.connections
+            .lock()
+            .unwrap()
+            .retain(|addr, _| addrs.contains(addr));
+    }
+}
+
+/// An idle connection is healthy when the peer neither closed it nor sent
+/// unsolicited data on it.
+fn is_healthy(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let healthy = match stream.peek(&mut [0u8; 1]) {
+        Err(e) => e.kind() == ErrorKind::WouldBlock,
+        Ok(_) => false,
+    };
+
+    healthy && stream.set_nonblocking(false).is_ok()
+}
+
+pub fn connection_pool() -> &'static ConnectionPool {
+    static CONNECTION_POOL: OnceLock<ConnectionPool> = OnceLock::new();
+    CONNECTION_POOL.get_or_init(ConnectionPool::new)
+}
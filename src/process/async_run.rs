@@ -0,0 +1,252 @@
+use std::{
+    io::{Error, ErrorKind},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::{self, JoinSet},
+    time,
+};
+
+use super::{leader_redirect, ConnectionState, Process, LEADER_ELECTION_WAIT, MAX_REDIRECTS};
+use crate::{
+    algorithms::{BroadcastOutcome, Logger},
+    async_algorithms::{AsyncBroadcast, AsyncP2PSend},
+    codec::CodecKind,
+    events::{Envelope, ProcessEvent},
+    pool::connection_pool,
+    service::{ServiceInstance, ServiceQuery},
+    shutdown::POLL_INTERVAL,
+};
+
+impl AsyncP2PSend for Process {}
+impl AsyncBroadcast for Process {}
+
+impl Process {
+    /// Same as [`Process::run`], with every connection and background loop
+    /// running as a task on the current tokio runtime.
+    pub async fn run_async(self: Arc<Self>) -> std::io::Result<()> {
+        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.port)).await?;
+        self.log(&format!("Started process on port {}", self.port));
+
+        self.connect_with_retries_async().await?;
+        self.watch_registry_async().await;
+        let mut tasks = JoinSet::new();
+
+        // Periodically check if the registry is still alive, renewing the lease if any
+        let process = self.clone();
+        tasks.spawn(async move {
+            while !process
+                .shutdown
+                .wait_timeout_async(process.heartbeat_interval)
+                .await
+            {
+                process.send_heartbeat_to_registry_async().await;
+                if process.needs_resync.swap(false, Ordering::Relaxed) {
+                    process.watch_registry_async().await;
+                }
+                connection_pool().evict_idle();
+            }
+        });
+
+        // Periodically send a message to a random process
+        let process = self.clone();
+        tasks.spawn(async move {
+            while !process
+                .shutdown
+                .wait_timeout_async(Duration::from_secs(20))
+                .await
+            {
+                let _ = process.send_to_random_process_async().await;
+            }
+        });
+
+        // Periodically broadcast a message to all processes
+        let process = self.clone();
+        tasks.spawn(async move {
+            while !process
+                .shutdown
+                .wait_timeout_async(Duration::from_secs(5))
+                .await
+            {
+                let _ = process.broadcast_to_processes_async().await;
+            }
+        });
+
+        // Listen for incoming events
+        while !self.shutdown.is_requested() {
+            match time::timeout(POLL_INTERVAL, listener.accept()).await {
+                Ok(Ok((stream, _))) => {
+                    let process = self.clone();
+                    tasks.spawn(async move { process.handle_connection_async(stream).await });
+                }
+                Ok(Err(e)) => self.log(&format!("Couldn't accept connection: {}", e)),
+                Err(_) => {}
+            }
+        }
+
+        while tasks.join_next().await.is_some() {}
+        self.deregister_async().await;
+        self.log("Stopped");
+        self.shutdown.outcome()
+    }
+
+    async fn handle_connection_async(self: &Arc<Self>, stream: TcpStream) {
+        let handle = |envelope| async move {
+            let process = self.clone();
+            task::spawn_blocking(move || process.handle_envelope(envelope))
+                .await
+                .unwrap_or_else(|e| {
+                    self.log(&format!("Couldn't handle envelope: {}", e));
+                    None
+                })
+        };
+        Process::serve_frames_async(
+            stream,
+            &self.shutdown,
+            self.as_ref(),
+            self.codec,
+            self.max_frame_size,
+            handle,
+            |_| None,
+        )
+        .await;
+    }
+
+    /// Same as [`Process::connect_with_retries`], on the current tokio
+    /// runtime.
+    async fn connect_with_retries_async(&self) -> std::io::Result<()> {
+        self.set_connection_state(ConnectionState::Connecting);
+        let mut attempt = 0;
+        loop {
+            let e = match self.connect_to_registry_async().await {
+                Ok(()) => {
+                    self.connected();
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+            attempt += 1;
+            match self.retry_delay(attempt, &e) {
+                Some(delay) if !self.shutdown.wait_timeout_async(delay).await => {}
+                _ => return Err(e),
+            }
+        }
+    }
+
+    async fn connect_to_registry_async(&self) -> std::io::Result<()> {
+        let mut unreachable = 0;
+        for _ in 0..MAX_REDIRECTS + self.registry_addresses.len() {
+            let connect_event = self.connect_event();
+            let response = Process::request_async(
+                &self.registry_address(),
+                &connect_event,
+                self.codec,
+                self.max_frame_size,
+            )
+            .await;
+            match leader_redirect(&response) {
+                Some(leader) => {
+                    if !self.follow_leader(leader) {
+                        self.shutdown.wait_timeout_async(LEADER_ELECTION_WAIT).await;
+                    }
+                }
+                None if response.is_err() && unreachable + 1 < self.registry_addresses.len() => {
+                    unreachable += 1;
+                    self.next_registry();
+                }
+                None => return self.handle_connect_response(response),
+            }
+        }
+
+        Err(Error::new(
+            ErrorKind::NotConnected,
+            "no leader of the registry cluster took the registration",
+        ))
+    }
+
+    /// Same as [`Process::lookup`], on the current tokio runtime.
+    pub async fn lookup_async(
+        registry_address: &str,
+        query: ServiceQuery,
+        codec: CodecKind,
+        max_frame_size: usize,
+    ) -> std::io::Result<Vec<ServiceInstance>> {
+        let lookup_event = Envelope::new(None, ProcessEvent::Lookup { query });
+        Process::lookup_result(
+            Process::request_async(registry_address, &lookup_event, codec, max_frame_size).await,
+        )
+    }
+
+    async fn watch_registry_async(&self) {
+        let watch_event = self.watch_event();
+        if let Err(e) =
+            Process::send_async(&self.registry_address(), &watch_event, self.max_frame_size).await
+        {
+            self.log(&format!("Couldn't watch registry: {}", e));
+            self.needs_resync.store(true, Ordering::Relaxed);
+        }
+    }
+
+    async fn deregister_async(&self) {
+        let deregister_event = self.deregister_event();
+        if let Err(e) = Process::send_async(
+            &self.registry_address(),
+            &deregister_event,
+            self.max_frame_size,
+        )
+        .await
+        {
+            self.log(&format!("Couldn't deregister from registry: {}", e));
+        }
+    }
+
+    async fn send_heartbeat_to_registry_async(&self) {
+        self.log("Sending heartbeat to registry...");
+
+        let must_register = match self.renew_lease_event() {
+            Some(renew_lease_event) => {
+                let response = Process::request_async(
+                    &self.registry_address(),
+                    &renew_lease_event,
+                    self.codec,
+                    self.max_frame_size,
+                )
+                .await;
+                self.handle_lease_response(response)
+            }
+            None => {
+                let registry_is_alive =
+                    Process::process_is_alive_async(self.registry_address()).await;
+                self.handle_registry_heartbeat(registry_is_alive)
+            }
+        };
+        if must_register {
+            match self.connect_with_retries_async().await {
+                Ok(()) => self.watch_registry_async().await,
+                Err(e) => self.shutdown.fail(e),
+            }
+        }
+    }
+
+    async fn send_to_random_process_async(&self) -> std::io::Result<usize> {
+        let (addr, message_event) = self.random_process_message()?;
+        Process::send_async(&addr, &message_event, self.max_frame_size).await
+    }
+
+    async fn broadcast_to_processes_async(&self) -> std::io::Result<BroadcastOutcome<usize>> {
+        // Build the message from a snapshot, the table isn't held while sending
+        let registered_processes = match self.registered_processes.try_lock() {
+            Ok(registered_processes) => registered_processes.clone(),
+            Err(_) => return Err(ErrorKind::Other.into()),
+        };
+        let (processes, broadcast_message) = self.broadcast_message(&registered_processes)?;
+
+        Ok(
+            Process::broadcast_to_all_async(&processes, &broadcast_message, self.max_frame_size)
+                .await,
+        )
+    }
+}
@@ -1,9 +1,11 @@
 use std::{
     collections::HashMap,
-    io::Read,
-    net::TcpListener,
-    process::exit,
-    sync::{Arc, Mutex},
+    io::{Error, ErrorKind},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     thread,
     time::Duration,
 };
@@ -11,22 +13,65 @@ use std::{
 use rand::Rng;
 
 use crate::{
-    algorithms::{Logger, PaxosAcceptor},
-    events::{Event, PaxosAcceptedValue, PaxosProposerEvent, ProcessEvent, RegistryEvent},
-    handle_buffer, Broadcast, P2PSend,
+    algorithms::{BroadcastOutcome, Logger, PaxosAcceptor},
+    codec::CodecKind,
+    events::{
+        Envelope, Event, PaxosAcceptedValue, PaxosProposerEvent, ProcessEvent, RegistryEvent,
+        CAPABILITIES, SUPPORTED_PROTOCOL_VERSIONS,
+    },
+    failure_detector::{FailureDetectorConfig, PhiAccrual, Suspicion},
+    pool::connection_pool,
+    service::{endpoints, MembershipDelta, ServiceInstance, ServiceQuery},
+    shutdown::{Shutdown, ShutdownHandle, POLL_INTERVAL},
+    Broadcast, P2PSend,
 };
 
-type Processes = Arc<Mutex<HashMap<u32, String>>>;
+#[cfg(feature = "async")]
+mod async_run;
+mod reconnect;
+
+pub use reconnect::{ConnectionState, ReconnectPolicy};
+
+type Processes = Arc<Mutex<HashMap<u32, ServiceInstance>>>;
 type AMu32 = Arc<Mutex<u32>>;
 
+/// Redirects to the leader of a registry cluster followed while
+/// registering, before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+/// How long to wait for a registry cluster without a leader to elect one.
+const LEADER_ELECTION_WAIT: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 pub struct Process {
     id: AMu32,
     port: u32,
-    registry_address: String,
+    /// Registry the process talks to, one of `registry_addresses` or the
+    /// leader of their cluster once a follower redirected to it.
+    registry_address: Arc<Mutex<String>>,
+    /// Registries the process was started with, tried in turn whenever the
+    /// current one stops answering.
+    registry_addresses: Vec<String>,
+    /// Position in `registry_addresses` of the last one tried.
+    registry_index: Arc<Mutex<usize>>,
+    instance: ServiceInstance,
     registered_processes: Processes,
+    /// Revision of the registry's table `registered_processes` matches.
+    membership_revision: Arc<Mutex<Option<u64>>>,
+    needs_resync: Arc<AtomicBool>,
+    heartbeat_interval: Duration,
+    failure_detector: FailureDetectorConfig,
+    registry_detector: Arc<Mutex<PhiAccrual>>,
+    /// Set when the registry may have forgotten this process, because it
+    /// missed a heartbeat or pushed a table without it.
+    must_register: Arc<AtomicBool>,
+    reconnect: ReconnectPolicy,
+    connection_state: Arc<Mutex<ConnectionState>>,
     paxos_sn: AMu32,
     paxos_av: Arc<Mutex<Option<PaxosAcceptedValue>>>,
+    codec: CodecKind,
+    max_frame_size: usize,
+    shutdown: Arc<Shutdown>,
 }
 
 impl P2PSend for Process {}
@@ -34,151 +79,544 @@ impl Broadcast for Process {}
 impl PaxosAcceptor for Process {}
 
 impl Process {
-    pub fn new(port: u32, registry_address: String) -> std::io::Result<Self> {
+    /// `instance` describes the service this process provides, only its
+    /// name, version, tags and metadata are sent to the registry. The process
+    /// registers with the first of `registry_addresses` that answers.
+    /// Frames over `max_frame_size` bytes are neither sent nor read.
+    pub fn new(
+        port: u32,
+        registry_addresses: Vec<String>,
+        codec: CodecKind,
+        max_frame_size: usize,
+        instance: ServiceInstance,
+        failure_detector: FailureDetectorConfig,
+        reconnect: ReconnectPolicy,
+    ) -> std::io::Result<Self> {
+        let registry_address = registry_addresses
+            .first()
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no registry address"))?;
         let _ = TcpListener::bind(format!("0.0.0.0:{}", port))?;
+        let heartbeat_interval = heartbeat_interval(instance.lease_ttl);
+        let instance = ServiceInstance {
+            instance_uuid: instance
+                .instance_uuid
+                .or_else(|| Some(format!("{:032x}", rand::random::<u128>()))),
+            ..instance
+        };
         Ok(Process {
             id: Arc::new(Mutex::new(0)),
-            port: port,
-            registry_address: registry_address,
+            port,
+            registry_address: Arc::new(Mutex::new(registry_address)),
+            registry_addresses,
+            registry_index: Arc::new(Mutex::new(0)),
+            instance,
             registered_processes: Arc::new(Mutex::new(HashMap::new())),
+            membership_revision: Arc::new(Mutex::new(None)),
+            needs_resync: Arc::new(AtomicBool::new(false)),
+            heartbeat_interval,
+            failure_detector,
+            registry_detector: Arc::new(Mutex::new(PhiAccrual::new(
+                heartbeat_interval,
+                &failure_detector,
+            ))),
+            must_register: Arc::new(AtomicBool::new(false)),
+            reconnect,
+            connection_state: Arc::new(Mutex::new(ConnectionState::Connecting)),
             paxos_sn: Arc::new(Mutex::new(0)),
             paxos_av: Arc::new(Mutex::new(None)),
+            codec,
+            max_frame_size,
+            shutdown: Arc::new(Shutdown::default()),
         })
     }
 
+    /// Runs the process until it is shut down, or until it gives up on its
+    /// registry, which is returned as an error.
     pub fn run(&self) -> std::io::Result<()> {
         let listener = TcpListener::bind(format!("0.0.0.0:{}", self.port))?;
+        self.serve(listener)
+    }
+
+    /// Runs the process on a background thread, returning once it listens.
+    pub fn start(self) -> std::io::Result<ShutdownHandle> {
+        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.port))?;
+        let shutdown = self.shutdown.clone();
+        let thread = thread::spawn(move || self.serve(listener));
+
+        Ok(ShutdownHandle::new(shutdown, thread))
+    }
+
+    fn serve(&self, listener: TcpListener) -> std::io::Result<()> {
         self.log(&format!("Started process on port {}", self.port));
 
-        self.connect_to_registry(self.registry_address.clone());
+        self.connect_with_retries()?;
+        self.watch_registry();
 
+        listener.set_nonblocking(true)?;
         thread::scope(|s| {
-            // Periodically check if the registry is still alive
-            s.spawn(move || loop {
-                thread::sleep(Duration::from_secs(5));
-
-                self.send_heartbeat_to_registry();
+            // Periodically check if the registry is still alive, renewing the lease if any
+            s.spawn(move || {
+                while !self.shutdown.wait_timeout(self.heartbeat_interval) {
+                    self.send_heartbeat_to_registry();
+                    if self.needs_resync.swap(false, Ordering::Relaxed) {
+                        self.watch_registry();
+                    }
+                    connection_pool().evict_idle();
+                }
             });
 
             // Periodically send a message to a random process
-            s.spawn(move || loop {
-                thread::sleep(Duration::from_secs(20));
-
-                match self.send_to_random_process() {
-                    Ok(_) => {}
-                    Err(_) => {}
+            s.spawn(move || {
+                while !self.shutdown.wait_timeout(Duration::from_secs(20)) {
+                    let _ = self.send_to_random_process();
                 }
             });
 
             // Periodically broadcast a message to all processes
-            s.spawn(move || loop {
-                thread::sleep(Duration::from_secs(5));
-
-                let registered_processes = self.registered_processes.try_lock();
-                if registered_processes.is_ok() {
-                    match self.broadcast_to_processes(&*registered_processes.unwrap()) {
-                        Ok(_) => {}
-                        Err(_) => {}
+            s.spawn(move || {
+                while !self.shutdown.wait_timeout(Duration::from_secs(5)) {
+                    if let Ok(registered_processes) = self.registered_processes.try_lock() {
+                        let _ = self.broadcast_to_processes(&registered_processes);
                     }
                 }
             });
 
             // Listen for incoming events
-            for stream in listener.incoming() {
-                let mut buffer = [0u8; 1000];
-
-                s.spawn(move || {
-                    let mut stream = stream.unwrap();
-                    let data_size = stream.read(&mut buffer).unwrap();
-
-                    let proposer_address = self.registry_address.clone();
-                    if data_size > 0 {
-                        let event = handle_buffer(buffer, data_size);
-
-                        match event {
-                            Some(event) => match event {
-                                Event::ProcessEvent(process_event) => {
-                                    self.handle_process_event(process_event);
-                                }
-                                Event::RegistryEvent(registry_event) => {
-                                    self.handle_registry_event(registry_event);
-                                }
-                                Event::PaxosProposerEvent(proposer_event) => {
-                                    self.handle_proposer_event(proposer_event, proposer_address);
-                                }
-                                Event::PaxosAcceptorEvent(_) => {}
-                            },
-                            None => {
-                                self.log("Received something else");
-                            }
-                        };
+            while !self.shutdown.is_requested() {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        s.spawn(move || self.handle_connection(stream));
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        self.shutdown.wait_timeout(POLL_INTERVAL);
                     }
-                });
+                    Err(e) => self.log(&format!("Couldn't accept connection: {}", e)),
+                }
             }
         });
-        Ok(())
+
+        self.deregister();
+        self.log("Stopped");
+        self.shutdown.outcome()
     }
 
-    fn connect_to_registry(&self, registry_address: String) {
-        self.log("Connecting to registry...");
-        let connect_event = &ProcessEvent::ConnectOnPort {
-            port: self.port.clone(),
+    fn handle_connection(&self, stream: TcpStream) {
+        let handle = |envelope| self.handle_envelope(envelope);
+        Process::serve_frames(
+            stream,
+            &self.shutdown,
+            self,
+            self.codec,
+            self.max_frame_size,
+            handle,
+            |_| None,
+        );
+    }
+
+    /// Dispatches an incoming envelope, returning the response to send back
+    /// if it was a request.
+    fn handle_envelope(&self, envelope: Envelope) -> Option<Envelope> {
+        match envelope.event {
+            Event::ProcessEvent(process_event) => {
+                self.handle_process_event(process_event);
+                None
+            }
+            Event::RegistryEvent(registry_event) => {
+                self.handle_registry_event(registry_event);
+                None
+            }
+            Event::PaxosProposerEvent(proposer_event) => {
+                Some(self.handle_proposer_event(proposer_event, envelope.correlation_id))
+            }
+            Event::PaxosAcceptorEvent(_) | Event::ClusterEvent(_) => None,
         }
-        .as_bytes_vec()[..];
-        match Process::send(&registry_address, connect_event) {
-            Ok(_) => {}
-            _ => {
-                self.log("Couldn't reach registry, exiting");
-                exit(1);
+    }
+
+    /// Registers with the registry, retrying as the reconnect policy says
+    /// until it succeeds, the registry rejects the process or the process
+    /// shuts down.
+    fn connect_with_retries(&self) -> std::io::Result<()> {
+        self.set_connection_state(ConnectionState::Connecting);
+        let mut attempt = 0;
+        loop {
+            let e = match self.connect_to_registry() {
+                Ok(()) => {
+                    self.connected();
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+            attempt += 1;
+            match self.retry_delay(attempt, &e) {
+                Some(delay) if !self.shutdown.wait_timeout(delay) => {}
+                _ => return Err(e),
             }
         }
     }
 
-    fn handle_registry_event(&self, registry_event: RegistryEvent) {
-        let self_id = &self.id;
-        let registered_processes = &self.registered_processes;
+    /// Starts watching the registry afresh, the time it went missing says
+    /// nothing about how often it answers.
+    fn connected(&self) {
+        *self.registry_detector.lock().unwrap() =
+            PhiAccrual::new(self.heartbeat_interval, &self.failure_detector);
+        self.must_register.store(false, Ordering::Relaxed);
+        self.set_connection_state(ConnectionState::Connected);
+    }
+
+    /// Returns how long to wait before retry `attempt` after registering
+    /// failed with `e`, or `None` if the process should give up.
+    fn retry_delay(&self, attempt: u32, e: &Error) -> Option<Duration> {
+        // A registry that rejected the process won't take it later
+        if e.kind() == ErrorKind::Unsupported {
+            self.set_connection_state(ConnectionState::GaveUp);
+            return None;
+        }
+        if self.reconnect.gives_up_after(attempt) {
+            self.log(&format!(
+                "Giving up on the registry after {} retries",
+                attempt - 1
+            ));
+            self.set_connection_state(ConnectionState::GaveUp);
+            return None;
+        }
+
+        let retry_in = self.reconnect.backoff(attempt);
+        self.log(&format!(
+            "Couldn't register, retrying in {:?} (attempt {})",
+            retry_in, attempt
+        ));
+        self.set_connection_state(ConnectionState::Reconnecting { attempt, retry_in });
+        Some(retry_in)
+    }
+
+    fn set_connection_state(&self, state: ConnectionState) {
+        let connection_state = &mut *self.connection_state.lock().unwrap();
+        if *connection_state == state {
+            return;
+        }
+        *connection_state = state;
+        if let Some(on_state_change) = &self.reconnect.on_state_change {
+            on_state_change(state);
+        }
+    }
+
+    fn connect_to_registry(&self) -> std::io::Result<()> {
+        let mut unreachable = 0;
+        for _ in 0..MAX_REDIRECTS + self.registry_addresses.len() {
+            let connect_event = self.connect_event();
+            let response = Process::request(
+                &self.registry_address(),
+                &connect_event,
+                self.codec,
+                self.max_frame_size,
+            );
+            match leader_redirect(&response) {
+                Some(leader) => {
+                    if !self.follow_leader(leader) {
+                        self.shutdown.wait_timeout(LEADER_ELECTION_WAIT);
+                    }
+                }
+                None if response.is_err() && unreachable + 1 < self.registry_addresses.len() => {
+                    unreachable += 1;
+                    self.next_registry();
+                }
+                None => return self.handle_connect_response(response),
+            }
+        }
+
+        Err(Error::new(
+            ErrorKind::NotConnected,
+            "no leader of the registry cluster took the registration",
+        ))
+    }
+
+    fn registry_address(&self) -> String {
+        self.registry_address.lock().unwrap().clone()
+    }
+
+    /// Moves on to the leader a follower of the registry cluster redirected
+    /// to. Returns false while the cluster has no leader.
+    fn follow_leader(&self, leader: Option<String>) -> bool {
+        match leader {
+            Some(leader) => {
+                self.log(&format!(
+                    "Registry isn't the leader of its cluster, moving to {}",
+                    leader
+                ));
+                *self.registry_address.lock().unwrap() = leader;
+                true
+            }
+            None => {
+                self.log("Registry cluster has no leader yet");
+                false
+            }
+        }
+    }
+
+    fn connect_event(&self) -> Envelope {
+        self.log("Connecting to registry...");
+        Envelope::new(
+            None,
+            ProcessEvent::ConnectOnPort {
+                port: self.port,
+                protocol_versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+                capabilities: CAPABILITIES.to_vec(),
+                // Known by its uuid, the process gets the id it had before
+                instance: Box::new(self.instance.clone()),
+            },
+        )
+    }
+
+    fn handle_connect_response(&self, response: std::io::Result<Envelope>) -> std::io::Result<()> {
+        match response {
+            Ok(Envelope {
+                event:
+                    Event::RegistryEvent(RegistryEvent::Rejected {
+                        reason,
+                        supported_versions,
+                        ..
+                    }),
+                ..
+            }) => {
+                self.log(&format!(
+                    "Registry rejected connection: {} (registry supports {:?})",
+                    reason, supported_versions
+                ));
+                Err(Error::new(ErrorKind::Unsupported, reason))
+            }
+            Ok(Envelope {
+                event: Event::RegistryEvent(registry_event),
+                ..
+            }) => {
+                self.handle_registry_event(registry_event);
+                Ok(())
+            }
+            Ok(_) => {
+                self.log("Unexpected response from registry");
+                Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "unexpected response from registry",
+                ))
+            }
+            Err(e) => {
+                self.log(&format!("Couldn't reach registry ({})", e));
+                Err(e)
+            }
+        }
+    }
+
+    /// Tells the registry this process is leaving, so it doesn't wait for a
+    /// failed heartbeat to notice.
+    fn deregister(&self) {
+        let deregister_event = self.deregister_event();
+        if let Err(e) = Process::send(
+            &self.registry_address(),
+            &deregister_event,
+            self.max_frame_size,
+        ) {
+            self.log(&format!("Couldn't deregister from registry: {}", e));
+        }
+    }
 
+    /// Subscribes to every change of the registry's table, resuming from the
+    /// last revision seen if there is one.
+    fn watch_registry(&self) {
+        let watch_event = self.watch_event();
+        if let Err(e) = Process::send(&self.registry_address(), &watch_event, self.max_frame_size) {
+            self.log(&format!("Couldn't watch registry: {}", e));
+            self.needs_resync.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn watch_event(&self) -> Vec<u8> {
+        let id = *self.id.lock().unwrap();
+        let since = *self.membership_revision.lock().unwrap();
+
+        Envelope::new(
+            Some(id),
+            ProcessEvent::Watch {
+                service_name: None,
+                port: self.port,
+                since,
+            },
+        )
+        .as_bytes_vec(self.codec)
+    }
+
+    fn deregister_event(&self) -> Vec<u8> {
+        let id = *self.id.lock().unwrap();
+        self.log("Deregistering from registry...");
+
+        let instance_uuid = self.instance.instance_uuid.clone();
+        Envelope::new(Some(id), ProcessEvent::Deregister { id, instance_uuid })
+            .as_bytes_vec(self.codec)
+    }
+
+    fn handle_registry_event(&self, registry_event: RegistryEvent) {
         match registry_event {
             RegistryEvent::Registered {
                 given_id,
-                registered_processes: update_processes,
+                registered_processes,
+                revision,
+                protocol_version,
+                capabilities,
             } => {
-                let local_registered_processes = registered_processes.try_lock();
-                let local_self_id = self_id.lock();
-                if local_registered_processes.is_ok() && local_self_id.is_ok() {
-                    let local_registered_processes = &mut *local_registered_processes.unwrap();
-
-                    let _ = std::mem::replace(local_registered_processes, update_processes);
-                    let _ = std::mem::replace(&mut *local_self_id.unwrap(), given_id);
-
-                    self.log(&format!(
-                        "Connected to registry, given id: {}\n Registered processes {:?}",
-                        given_id, local_registered_processes
-                    ));
+                *self.id.lock().unwrap() = given_id;
+                self.log(&format!(
+                    "Connected to registry, given id: {}, protocol version {}, capabilities {:?}",
+                    given_id, protocol_version, capabilities
+                ));
+                // A registry restarted without its log counts revisions from
+                // scratch again
+                *self.membership_revision.lock().unwrap() = None;
+                self.replace_registered_processes(revision, registered_processes);
+            }
+            RegistryEvent::Rejected {
+                reason,
+                supported_versions,
+                ..
+            } => {
+                self.log(&format!(
+                    "Registry rejected connection: {} (registry supports {:?})",
+                    reason, supported_versions
+                ));
+            }
+            RegistryEvent::UpdateRegisteredProcesses {
+                revision,
+                processes,
+            } => {
+                let id = *self.id.lock().unwrap();
+                if id != 0 && !processes.contains_key(&id) {
+                    self.must_register.store(true, Ordering::Relaxed);
                 }
+                self.replace_registered_processes(revision, processes)
+            }
+            RegistryEvent::LookupResult { .. } => {
+                self.log("Lookup result outside of a request");
+            }
+            RegistryEvent::LeaseRenewed { .. } | RegistryEvent::LeaseExpired { .. } => {
+                self.log("Lease renewal outside of a request");
+            }
+            RegistryEvent::NotLeader { .. } => {
+                self.log("Leader redirect outside of a request");
             }
-            RegistryEvent::UpdateRegisteredProcesses(update_processes) => {
-                let local_registered_processes = registered_processes.try_lock();
-                if local_registered_processes.is_ok() {
-                    let local_registered_processes = &mut *local_registered_processes.unwrap();
+            RegistryEvent::MembershipSnapshot {
+                revision,
+                instances,
+            } => self.replace_registered_processes(revision, instances),
+            RegistryEvent::MembershipDeltas {
+                since,
+                revision,
+                deltas,
+            } => self.apply_membership_deltas(since, revision, deltas),
+        }
+    }
+
+    /// Replaces the local table with the registry's table at `revision`,
+    /// unless a newer one was applied already.
+    fn replace_registered_processes(
+        &self,
+        revision: u64,
+        processes: HashMap<u32, ServiceInstance>,
+    ) {
+        let membership_revision = &mut *self.membership_revision.lock().unwrap();
+        if let Some(current_revision) = *membership_revision {
+            if revision < current_revision {
+                self.log(&format!(
+                    "Ignoring stale table at revision {}, already at {}",
+                    revision, current_revision
+                ));
+                return;
+            }
+        }
 
-                    let _ = std::mem::replace(local_registered_processes, update_processes);
+        let local_registered_processes = &mut *self.registered_processes.lock().unwrap();
+        *local_registered_processes = processes;
+        *membership_revision = Some(revision);
+        self.registered_processes_changed(local_registered_processes);
+        self.log(&format!(
+            "Registered processes at revision {}: {:?}",
+            revision,
+            endpoints(local_registered_processes)
+        ));
+    }
 
-                    self.log(&format!(
-                        "Updating registered processes: {:?}",
-                        local_registered_processes
-                    ));
-                }
+    fn apply_membership_deltas(&self, since: u64, revision: u64, deltas: Vec<MembershipDelta>) {
+        let membership_revision = &mut *self.membership_revision.lock().unwrap();
+
+        match *membership_revision {
+            // Deltas up to the current revision may have come in with a full table
+            Some(current_revision) if since <= current_revision && current_revision < revision => {
+                let local_registered_processes = &mut *self.registered_processes.lock().unwrap();
+                deltas
+                    .iter()
+                    .filter(|delta| delta.revision > current_revision)
+                    .for_each(|delta| {
+                        self.log(&format!(
+                            "Membership change at revision {}: {:?}",
+                            delta.revision, delta.change
+                        ));
+                        delta.change.apply(local_registered_processes);
+                    });
+
+                *membership_revision = Some(revision);
+                self.registered_processes_changed(local_registered_processes);
+            }
+            Some(current_revision) if revision <= current_revision => {
+                self.log(&format!(
+                    "Ignoring membership deltas up to revision {}, already at {}",
+                    revision, current_revision
+                ));
+            }
+            _ => {
+                self.log(&format!(
+                    "Missed membership changes between revisions {:?} and {}, resyncing",
+                    membership_revision, since
+                ));
+                self.needs_resync.store(true, Ordering::Relaxed);
             }
         }
     }
 
+    /// Drops pooled connections to processes that left.
+    fn registered_processes_changed(&self, processes: &HashMap<u32, ServiceInstance>) {
+        let registry_address = self.registry_address();
+        connection_pool().retain_peers(endpoints(processes).values().chain([&registry_address]));
+    }
+
+    /// Asks the registry for the instances matching `query`.
+    pub fn lookup(
+        registry_address: &str,
+        query: ServiceQuery,
+        codec: CodecKind,
+        max_frame_size: usize,
+    ) -> std::io::Result<Vec<ServiceInstance>> {
+        let lookup_event = Envelope::new(None, ProcessEvent::Lookup { query });
+        Process::lookup_result(Process::request(
+            registry_address,
+            &lookup_event,
+            codec,
+            max_frame_size,
+        ))
+    }
+
+    fn lookup_result(response: std::io::Result<Envelope>) -> std::io::Result<Vec<ServiceInstance>> {
+        match response? {
+            Envelope {
+                event: Event::RegistryEvent(RegistryEvent::LookupResult { instances, .. }),
+                ..
+            } => Ok(instances),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "unexpected response to a lookup",
+            )),
+        }
+    }
+
     fn handle_process_event(&self, process_event: ProcessEvent) {
-        match process_event {
-            ProcessEvent::Message { from, msg } => {
-                self.log(&format!("Received from {}: {}", from, msg));
-            }
-            _ => {}
+        if let ProcessEvent::Message { from, msg } = process_event {
+            self.log(&format!("Received from {}: {}", from, msg));
         }
     }
 
@@ -187,9 +625,14 @@ impl Process {
     //      - Sn = sequence number to which the acceptor responded with a promise
     //      - AV = (Sn,V) last couple that the acceptor accepted
     // Init : Sn = 0; AV=None
-    fn handle_proposer_event(&self, proposer_event: PaxosProposerEvent, proposer_address: String) {
+    fn handle_proposer_event(
+        &self,
+        proposer_event: PaxosProposerEvent,
+        correlation_id: u64,
+    ) -> Envelope {
         let local_seq_number = &self.paxos_sn;
         let paxos_accepted_value = &self.paxos_av;
+        let self_id = *self.id.lock().unwrap();
         match proposer_event {
             PaxosProposerEvent::Prepare { seq_number } => {
                 self.log(&format!(
@@ -201,11 +644,12 @@ impl Process {
                 // Promise only if seq_number > Sn
                 if seq_number >= *local_seq_number {
                     let paxos_accepted_value = &*paxos_accepted_value.lock().unwrap();
-                    Process::promise(seq_number, *paxos_accepted_value, proposer_address);
                     // Update Sn
-                    let _ = std::mem::replace(local_seq_number, seq_number);
+                    *local_seq_number = seq_number;
+
+                    Process::promise(self_id, correlation_id, seq_number, *paxos_accepted_value)
                 } else {
-                    Process::no_promise(proposer_address);
+                    Process::no_promise(self_id, correlation_id)
                 }
             }
             PaxosProposerEvent::RequestAccept { seq_number, value } => {
@@ -218,11 +662,16 @@ impl Process {
                 // Accept only if seq_number >= Sn
                 if seq_number >= *local_seq_number {
                     let paxos_accepted_value = &mut *paxos_accepted_value.lock().unwrap();
-                    let _ = std::mem::replace(paxos_accepted_value, Some(value));
-
-                    Process::respond_accept(seq_number, *paxos_accepted_value, proposer_address);
+                    *paxos_accepted_value = Some(value);
+
+                    Process::respond_accept(
+                        self_id,
+                        correlation_id,
+                        seq_number,
+                        *paxos_accepted_value,
+                    )
                 } else {
-                    Process::no_promise(proposer_address);
+                    Process::no_promise(self_id, correlation_id)
                 }
             }
         }
@@ -231,31 +680,143 @@ impl Process {
     fn send_heartbeat_to_registry(&self) {
         self.log("Sending heartbeat to registry...");
 
-        if Process::process_is_alive(self.registry_address.to_owned()) {
+        let must_register = match self.renew_lease_event() {
+            Some(renew_lease_event) => {
+                let response = Process::request(
+                    &self.registry_address(),
+                    &renew_lease_event,
+                    self.codec,
+                    self.max_frame_size,
+                );
+                self.handle_lease_response(response)
+            }
+            None => {
+                let registry_is_alive = Process::process_is_alive(self.registry_address());
+                self.handle_registry_heartbeat(registry_is_alive)
+            }
+        };
+        if must_register {
+            match self.connect_with_retries() {
+                Ok(()) => self.watch_registry(),
+                Err(e) => self.shutdown.fail(e),
+            }
+        }
+    }
+
+    fn renew_lease_event(&self) -> Option<Envelope> {
+        self.instance.lease_ttl?;
+        let id = *self.id.lock().unwrap();
+
+        Some(Envelope::new(Some(id), ProcessEvent::RenewLease { id }))
+    }
+
+    /// Returns whether the process must register again.
+    fn handle_lease_response(&self, response: std::io::Result<Envelope>) -> bool {
+        match response {
+            Ok(Envelope {
+                event: Event::RegistryEvent(RegistryEvent::LeaseRenewed { ttl, .. }),
+                ..
+            }) => {
+                self.log(&format!("Lease renewed for {:?}", ttl));
+                self.handle_registry_heartbeat(true)
+            }
+            Ok(Envelope {
+                event: Event::RegistryEvent(RegistryEvent::NotLeader { leader, .. }),
+                ..
+            }) => {
+                self.follow_leader(leader);
+                self.handle_registry_heartbeat(true)
+            }
+            Ok(Envelope {
+                event: Event::RegistryEvent(RegistryEvent::LeaseExpired { .. }),
+                ..
+            }) => {
+                self.handle_registry_heartbeat(true);
+                self.log("Lease lapsed, registering again");
+                true
+            }
+            Ok(_) => {
+                self.log("Unexpected response to a lease renewal");
+                false
+            }
+            Err(_) => self.handle_registry_heartbeat(false),
+        }
+    }
+
+    /// Feeds a heartbeat to the registry's failure detector. Returns whether
+    /// the process must register again, which keeps its id if the registry
+    /// still knows it. Once the registry is suspected the process moves on to
+    /// the next one and registers when that answers, once it's dead the
+    /// process registers right away and retries until it gives up.
+    fn handle_registry_heartbeat(&self, registry_is_alive: bool) -> bool {
+        let registry_detector = &mut *self.registry_detector.lock().unwrap();
+        if registry_is_alive {
+            registry_detector.heartbeat();
+            if self.must_register.swap(false, Ordering::Relaxed) {
+                self.log("Registry may have forgotten this process, registering again");
+                return true;
+            }
             self.log("Registry is alive");
-        } else {
-            self.log("Registry is dead, exiting");
-            exit(0);
+            return false;
+        }
+
+        // A single missed heartbeat is no reason to leave the registry
+        let phi = registry_detector.phi();
+        match self.failure_detector.assess(phi) {
+            Suspicion::Alive => {
+                self.log(&format!("Registry missed a heartbeat (phi {:.2})", phi));
+                false
+            }
+            Suspicion::Suspect => {
+                self.log(&format!("Registry is suspected dead (phi {:.2})", phi));
+                self.must_register.store(true, Ordering::Relaxed);
+                self.next_registry();
+                false
+            }
+            Suspicion::Dead => {
+                self.log(&format!("Registry is dead (phi {:.2}), reconnecting", phi));
+                self.next_registry();
+                true
+            }
         }
     }
 
-    fn get_process_addr(id: u32, processes: &HashMap<u32, String>) -> Option<String> {
-        match processes.get(&id) {
-            Some(addr) => Some(addr.to_owned()),
-            None => None,
+    /// Moves on to the next registry of the list once the current one
+    /// stopped answering.
+    fn next_registry(&self) {
+        let registry_index = &mut *self.registry_index.lock().unwrap();
+        *registry_index = (*registry_index + 1) % self.registry_addresses.len();
+        let next_address = &self.registry_addresses[*registry_index];
+
+        let registry_address = &mut *self.registry_address.lock().unwrap();
+        if registry_address != next_address {
+            self.log(&format!(
+                "Registry at {} isn't answering, moving on to {}",
+                registry_address, next_address
+            ));
+            *registry_address = next_address.clone();
         }
     }
 
+    fn get_process_addr(id: u32, processes: &HashMap<u32, ServiceInstance>) -> Option<String> {
+        processes.get(&id).map(|instance| instance.endpoint())
+    }
+
     fn send_to_random_process(&self) -> std::io::Result<usize> {
+        let (addr, message_event) = self.random_process_message()?;
+        Process::send(&addr, &message_event, self.max_frame_size)
+    }
+
+    /// Picks another registered process and builds a message for it.
+    fn random_process_message(&self) -> std::io::Result<(String, Vec<u8>)> {
         let processes = self.registered_processes.try_lock();
         let self_id = self.id.try_lock();
-        if processes.is_ok() && self_id.is_ok() {
-            let processes = &mut *processes.unwrap();
-            let self_id = *&self_id.unwrap().to_owned();
+        if let (Ok(processes), Ok(self_id)) = (processes, self_id) {
+            let self_id = *self_id;
 
             if processes.len() > 1 {
                 let mut rng = rand::thread_rng();
-                let process_ids: &Vec<u32> = &processes.iter().map(|(k, _)| k.to_owned()).collect();
+                let process_ids: &Vec<u32> = &processes.keys().copied().collect();
                 let mut random_index = rng.gen_range(0..process_ids.len());
 
                 let mut process_id = process_ids.get(random_index).unwrap().to_owned();
@@ -265,14 +826,17 @@ impl Process {
                     process_id = process_ids.get(random_index).unwrap().to_owned();
                 }
 
-                let message_event = &ProcessEvent::Message {
-                    from: self_id,
-                    msg: "P2P message".to_owned(),
-                }
-                .as_bytes_vec()[..];
-
-                match Process::get_process_addr(process_id, processes) {
-                    Some(addr) => Process::send(&addr, message_event),
+                let message_event = Envelope::new(
+                    Some(self_id),
+                    ProcessEvent::Message {
+                        from: self_id,
+                        msg: "P2P message".to_owned(),
+                    },
+                )
+                .as_bytes_vec(self.codec);
+
+                match Process::get_process_addr(process_id, &processes) {
+                    Some(addr) => Ok((addr, message_event)),
                     None => Err(std::io::ErrorKind::AddrNotAvailable.into()),
                 }
             } else {
@@ -285,23 +849,41 @@ impl Process {
         }
     }
 
-    fn broadcast_to_processes(&self, processes: &HashMap<u32, String>) -> std::io::Result<usize> {
+    fn broadcast_to_processes(
+        &self,
+        processes: &HashMap<u32, ServiceInstance>,
+    ) -> std::io::Result<BroadcastOutcome<usize>> {
+        let (processes, broadcast_message) = self.broadcast_message(processes)?;
+        Ok(Process::broadcast_to_all(
+            &processes,
+            &broadcast_message,
+            self.max_frame_size,
+        ))
+    }
+
+    /// Builds a broadcast message and the processes to send it to, i.e.
+    /// everyone but this process.
+    fn broadcast_message(
+        &self,
+        processes: &HashMap<u32, ServiceInstance>,
+    ) -> std::io::Result<(HashMap<u32, String>, Vec<u8>)> {
         if processes.len() > 1 {
-            let self_id = self.id.try_lock();
-            if self_id.is_ok() {
-                let self_id = &*self_id.unwrap();
-                let processes: HashMap<u32, String> = processes
-                    .iter()
-                    .filter(|(&k, _)| k != *self_id)
-                    .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            if let Ok(self_id) = self.id.try_lock() {
+                let self_id = &*self_id;
+                let processes: HashMap<u32, String> = endpoints(processes)
+                    .into_iter()
+                    .filter(|(k, _)| k != self_id)
                     .collect();
 
-                let broadcast_message = &ProcessEvent::Message {
-                    from: *self_id,
-                    msg: "Broadcast message".to_owned(),
-                }
-                .as_bytes_vec()[..];
-                Process::broadcast_to_all(&processes, broadcast_message)
+                let broadcast_message = Envelope::new(
+                    Some(*self_id),
+                    ProcessEvent::Message {
+                        from: *self_id,
+                        msg: "Broadcast message".to_owned(),
+                    },
+                )
+                .as_bytes_vec(self.codec);
+                Ok((processes, broadcast_message))
             } else {
                 // Couldn't lock self_id
                 Err(std::io::ErrorKind::Other.into())
@@ -313,17 +895,34 @@ impl Process {
     }
 }
 
+/// Leases are renewed a few times per ttl, so a single lost renewal doesn't
+/// let them lapse.
+fn heartbeat_interval(lease_ttl: Option<Duration>) -> Duration {
+    let interval = Duration::from_secs(5);
+    match lease_ttl {
+        Some(ttl) => (ttl / 3).clamp(POLL_INTERVAL, interval),
+        None => interval,
+    }
+}
+
 impl Logger for Process {
     fn what_is_self(&self) -> String {
         "Process".to_string()
     }
 
     fn what_is_id(&self) -> Option<u32> {
-        let id = self.id.try_lock();
-        if id.is_ok() {
-            Some(*id.unwrap())
-        } else {
-            None
-        }
+        self.id.try_lock().ok().map(|id| *id)
+    }
+}
+
+/// The leader a follower of a registry cluster redirected to, `Some(None)`
+/// while the cluster has none.
+fn leader_redirect(response: &std::io::Result<Envelope>) -> Option<Option<String>> {
+    match response {
+        Ok(Envelope {
+            event: Event::RegistryEvent(RegistryEvent::NotLeader { leader, .. }),
+            ..
+        }) => Some(leader.clone()),
+        _ => None,
     }
 }
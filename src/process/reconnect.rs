@@ -0,0 +1,73 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use rand::Rng;
+
+/// Where a process stands with its registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Registering, for the first time or again.
+    Connecting,
+    Connected,
+    /// The last registration failed, the next one is tried in `retry_in`.
+    Reconnecting {
+        attempt: u32,
+        retry_in: Duration,
+    },
+    /// Out of retries, or rejected by the registry, the process stops.
+    GaveUp,
+}
+
+/// How a process retries registering once its registry stops answering,
+/// waiting exponentially longer between attempts.
+#[derive(Clone)]
+pub struct ReconnectPolicy {
+    /// Wait before the first retry, doubled on every following one.
+    pub initial_backoff: Duration,
+    /// Cap on the wait between retries.
+    pub max_backoff: Duration,
+    /// Retries before the process gives up and stops, `None` to retry
+    /// forever.
+    pub max_retries: Option<u32>,
+    /// Called whenever the process moves to another [`ConnectionState`].
+    pub on_state_change: Option<Arc<dyn Fn(ConnectionState) + Send + Sync>>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_retries: Some(10),
+            on_state_change: None,
+        }
+    }
+}
+
+impl fmt::Debug for ReconnectPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectPolicy")
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("max_retries", &self.max_retries)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn gives_up_after(&self, attempt: u32) -> bool {
+        self.max_retries
+            .is_some_and(|max_retries| attempt > max_retries)
+    }
+
+    /// Wait before retry `attempt`, counted from 1. Randomized between half
+    /// and all of the exponential backoff, so processes that lost the same
+    /// registry don't all come back at once.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        rand::thread_rng().gen_range(backoff / 2..=backoff)
+    }
+}
@@ -0,0 +1,360 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::{self, JoinSet},
+    time,
+};
+
+use super::{
+    failed_probes, health::HEALTH_CHECK_TICK, lease::LEASE_CHECK_INTERVAL,
+    persistence::STORAGE_TICK, replication::ELECTION_TICK, Registry, HEARTBEAT_INTERVAL,
+};
+use crate::{
+    algorithms::{Broadcast, BroadcastMode, BroadcastOutcome, Logger},
+    async_algorithms::{AsyncBroadcast, AsyncP2PSend, AsyncPaxosProposer},
+    events::Envelope,
+    pool::connection_pool,
+    shutdown::POLL_INTERVAL,
+};
+
+impl AsyncP2PSend for Registry {}
+impl AsyncBroadcast for Registry {}
+impl AsyncPaxosProposer for Registry {}
+
+impl Registry {
+    /// Same as [`Registry::run`], with every connection and background loop
+    /// running as a task on the current tokio runtime.
+    pub async fn run_async(self: Arc<Self>, addr: &str) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        self.recover()?;
+        self.log(&format!("Started registry on {}", addr));
+        let mut tasks = JoinSet::new();
+
+        // Task to check all processes if alive and broadcast the processes table
+        let registry = self.clone();
+        tasks.spawn(async move {
+            while !registry
+                .shutdown
+                .wait_timeout_async(HEARTBEAT_INTERVAL)
+                .await
+            {
+                registry.send_heartbeat_async().await;
+                let _ = registry.broadcast_registered_processes_async().await;
+                connection_pool().evict_idle();
+            }
+        });
+
+        // Task to start paxos consensus instance
+        let registry = self.clone();
+        tasks.spawn(async move {
+            while !registry
+                .shutdown
+                .wait_timeout_async(Duration::from_secs(10))
+                .await
+            {
+                if let Some(acceptors) = registry.check_consensus_status() {
+                    registry
+                        .run_consensus_instance_async(registry.paxos_seq_number, &acceptors)
+                        .await;
+                }
+            }
+        });
+
+        // Task to remove processes whose lease lapsed
+        let registry = self.clone();
+        tasks.spawn(async move {
+            while !registry
+                .shutdown
+                .wait_timeout_async(LEASE_CHECK_INTERVAL)
+                .await
+            {
+                registry.expire_leases();
+            }
+        });
+
+        // Task to run health checks as they fall due, checks block so each
+        // runs on the blocking pool, bounded by its timeout
+        let registry = self.clone();
+        tasks.spawn(async move {
+            while !registry
+                .shutdown
+                .wait_timeout_async(HEALTH_CHECK_TICK)
+                .await
+            {
+                for (instance, check) in registry.due_health_checks() {
+                    let registry = registry.clone();
+                    task::spawn_blocking(move || registry.run_health_check(instance, check));
+                }
+            }
+        });
+
+        // Task to sync and compact the log
+        let registry = self.clone();
+        tasks.spawn(async move {
+            while !registry.shutdown.wait_timeout_async(STORAGE_TICK).await {
+                registry.maintain_storage(false);
+            }
+        });
+
+        // Task to push membership changes to watchers as they happen
+        let registry = self.clone();
+        tasks.spawn(async move {
+            while !registry.shutdown.wait_timeout_async(POLL_INTERVAL).await {
+                if registry.pending_push.take(Duration::ZERO) {
+                    registry.push_to_watchers_async().await;
+                }
+            }
+        });
+
+        if let Some(cluster) = &self.cluster {
+            // Task to elect a new leader once the current one goes quiet
+            let registry = self.clone();
+            tasks.spawn(async move {
+                while !registry.shutdown.wait_timeout_async(ELECTION_TICK).await {
+                    registry.run_election_async().await;
+                }
+            });
+
+            // Task to replicate changes to followers while leading, early
+            // when the table changed
+            let registry = self.clone();
+            let heartbeat_interval = cluster.heartbeat_interval;
+            tasks.spawn(async move {
+                let mut last_replication = Instant::now();
+                while !registry.shutdown.wait_timeout_async(ELECTION_TICK).await {
+                    if registry.pending_replication.take(Duration::ZERO)
+                        || last_replication.elapsed() >= heartbeat_interval
+                    {
+                        last_replication = Instant::now();
+                        registry.replicate_async(heartbeat_interval).await;
+                    }
+                }
+            });
+        }
+
+        // Listen for incoming events
+        while !self.shutdown.is_requested() {
+            match time::timeout(POLL_INTERVAL, listener.accept()).await {
+                Ok(Ok((stream, _))) => {
+                    let registry = self.clone();
+                    tasks.spawn(async move { registry.handle_connection_async(stream).await });
+                }
+                Ok(Err(e)) => self.log(&format!("Couldn't accept connection: {}", e)),
+                Err(_) => {}
+            }
+        }
+
+        while tasks.join_next().await.is_some() {}
+        self.maintain_storage(true);
+        self.log("Stopped");
+        Ok(())
+    }
+
+    async fn handle_connection_async(self: &Arc<Self>, stream: TcpStream) {
+        let Ok(peer_addr) = stream.peer_addr().map(|peer_addr| peer_addr.ip()) else {
+            return;
+        };
+        Registry::serve_frames_async(
+            stream,
+            &self.shutdown,
+            self.as_ref(),
+            self.codec,
+            self.max_frame_size,
+            |envelope| self.respond_async(peer_addr, envelope),
+            |e| self.reject_envelope(peer_addr, e),
+        )
+        .await;
+    }
+
+    /// Same as [`Registry::respond`], on the current tokio runtime.
+    async fn respond_async(
+        self: &Arc<Self>,
+        peer_addr: IpAddr,
+        envelope: Envelope,
+    ) -> Option<Envelope> {
+        if self.must_forward(&envelope) {
+            if let Some(leader) = self.forward_target(&envelope) {
+                let forwarded = envelope.as_bytes_vec(self.codec);
+                if let Err(e) = Registry::send_async(&leader, &forwarded, self.max_frame_size).await
+                {
+                    self.log(&format!("Couldn't forward to leader {}: {}", leader, e));
+                }
+            }
+            return None;
+        }
+
+        // Handling locks the tables and writes the log, which blocks
+        let registry = self.clone();
+        let response = task::spawn_blocking(move || registry.handle_envelope(peer_addr, envelope))
+            .await
+            .unwrap_or_else(|e| {
+                self.log(&format!("Couldn't handle envelope: {}", e));
+                None
+            })?;
+        match self.needs_quorum(&response) {
+            Some(revision) if !self.replicate_to_quorum_async(revision).await => {
+                Some(self.not_replicated(response, revision))
+            }
+            _ => Some(response),
+        }
+    }
+
+    /// Same as [`Registry::replicate_to_quorum`], on the current tokio
+    /// runtime.
+    async fn replicate_to_quorum_async(self: &Arc<Self>, revision: u64) -> bool {
+        let Some(cluster) = &self.cluster else {
+            return true;
+        };
+
+        let deadline = Instant::now() + cluster.election_timeout;
+        while !self.replicated_by_quorum(revision) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || !self.is_leader() {
+                return false;
+            }
+            self.replicate_with_async(BroadcastMode::Quorum(cluster.quorum() - 1), remaining)
+                .await;
+            if !self.replicated_by_quorum(revision) {
+                self.shutdown.wait_timeout_async(ELECTION_TICK).await;
+            }
+        }
+        true
+    }
+
+    /// Same as [`Registry::send_heartbeat`], on the current tokio runtime.
+    async fn send_heartbeat_async(&self) {
+        if let Some(processes) = self.heartbeat_targets() {
+            let deadline = Instant::now() + Registry::BROADCAST_DEADLINE;
+            let mut outcome =
+                Registry::broadcast_to_all_async(&processes, &[], self.max_frame_size).await;
+            for _ in 0..self.failure_detector.probe_retries {
+                let failed = failed_probes(&processes, &outcome);
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if failed.is_empty() || remaining.is_zero() {
+                    break;
+                }
+                outcome.extend(
+                    Registry::broadcast_with_async(
+                        &failed,
+                        &[],
+                        self.max_frame_size,
+                        BroadcastMode::All,
+                        remaining,
+                    )
+                    .await,
+                );
+            }
+            self.handle_heartbeat_outcome(&processes, outcome);
+        }
+    }
+
+    /// Same as [`Registry::run_election`], on the current tokio runtime.
+    async fn run_election_async(&self) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+
+        if let Some((term, request)) = self.vote_request() {
+            let outcome = Registry::request_with_async(
+                &cluster.peers,
+                &request,
+                self.codec,
+                self.max_frame_size,
+                BroadcastMode::All,
+                cluster.election_timeout / 2,
+            )
+            .await;
+            self.count_votes(term, outcome);
+        }
+    }
+
+    /// Same as [`Registry::replicate`], on the current tokio runtime.
+    async fn replicate_async(self: &Arc<Self>, heartbeat_interval: Duration) {
+        self.replicate_with_async(BroadcastMode::All, heartbeat_interval)
+            .await;
+    }
+
+    async fn replicate_with_async(self: &Arc<Self>, mode: BroadcastMode, deadline: Duration) {
+        let mut requests = JoinSet::new();
+        for (peers, request) in self.replication_requests() {
+            let registry = self.clone();
+            requests.spawn(async move {
+                let outcome = Registry::request_with_async(
+                    &peers,
+                    &request,
+                    registry.codec,
+                    registry.max_frame_size,
+                    mode,
+                    deadline,
+                )
+                .await;
+                outcome
+                    .into_iter()
+                    .for_each(|(id, response)| registry.replicated(id, response));
+            });
+        }
+        while requests.join_next().await.is_some() {}
+    }
+
+    async fn broadcast_registered_processes_async(
+        &self,
+    ) -> std::io::Result<BroadcastOutcome<usize>> {
+        let (processes, registry_event) = self.registered_processes_update()?;
+
+        let outcome =
+            Registry::broadcast_to_all_async(&processes, &registry_event, self.max_frame_size)
+                .await;
+        self.log_failed_pushes(&outcome);
+
+        Ok(outcome)
+    }
+
+    async fn push_to_watchers_async(&self) {
+        for (addr, revision, registry_event) in self.pending_watcher_updates() {
+            let result = Registry::send_async(&addr, &registry_event, self.max_frame_size).await;
+            self.watcher_pushed(&addr, revision, result);
+        }
+    }
+
+    async fn run_consensus_instance_async(
+        &self,
+        seq_number: u32,
+        acceptors: &HashMap<u32, String>,
+    ) {
+        let mut accept_request = None;
+        for response in
+            Registry::prepare_async(self.codec, self.max_frame_size, seq_number, acceptors)
+                .await
+                .into_values()
+                .flatten()
+        {
+            if let Some(request) = self.handle_acceptor_response(response) {
+                accept_request = Some(request);
+            }
+        }
+
+        if let Some((seq_number, value)) = accept_request {
+            for response in Registry::request_accept_async(
+                self.codec,
+                self.max_frame_size,
+                seq_number,
+                value,
+                acceptors,
+            )
+            .await
+            .into_values()
+            .flatten()
+            {
+                self.handle_acceptor_response(response);
+            }
+        }
+
+        self.finish_consensus_instance();
+    }
+}
@@ -0,0 +1,105 @@
+use std::time::{Duration, Instant};
+
+use super::Registry;
+use crate::{
+    algorithms::Logger,
+    health::{CheckState, HealthCheck, HealthStatus},
+    service::ServiceInstance,
+};
+
+/// How often the registry looks for checks to run.
+pub const HEALTH_CHECK_TICK: Duration = Duration::from_millis(500);
+
+impl Registry {
+    /// Schedules the health check `instance` asked for, returning the check
+    /// actually scheduled. Checks the policy refuses, e.g. taken by a
+    /// previous leader with a laxer one, aren't.
+    pub(super) fn schedule_health_check(&self, instance: &ServiceInstance) -> Option<HealthCheck> {
+        let check = instance.health_check.clone()?;
+        if let Some(reason) = self.health_check_policy.refusal(&check) {
+            self.log(&format!(
+                "Not checking process {}: {}",
+                instance.instance_id, reason
+            ));
+            return None;
+        }
+        let check = self.health_check_policy.bound(check);
+        self.health_checks
+            .lock()
+            .unwrap()
+            .insert(instance.instance_id, CheckState::new(check.clone()));
+
+        Some(check)
+    }
+
+    /// The instances whose check should run now, along with the check. They
+    /// aren't handed out again until their result is recorded. Only the
+    /// leader of a cluster checks processes.
+    pub(super) fn due_health_checks(&self) -> Vec<(ServiceInstance, HealthCheck)> {
+        if !self.is_leader() {
+            return vec![];
+        }
+        let processes = self.processes.lock().unwrap();
+        let health_checks = &mut *self.health_checks.lock().unwrap();
+
+        let now = Instant::now();
+        health_checks
+            .iter_mut()
+            .filter_map(|(id, state)| {
+                let instance = processes.get(id)?;
+                state
+                    .start_if_due(now)
+                    .then(|| (instance.clone(), state.check.clone()))
+            })
+            .collect()
+    }
+
+    /// Runs `check` against `instance` and records the result.
+    pub(super) fn run_health_check(&self, instance: ServiceInstance, check: HealthCheck) {
+        let result = check.run(&instance);
+        self.health_checked(instance.instance_id, result);
+    }
+
+    fn health_checked(&self, id: u32, result: HealthStatus) {
+        let processes = &mut *self.processes.lock().unwrap();
+        let health_checks = &mut *self.health_checks.lock().unwrap();
+
+        // The instance may have left while the check ran
+        let (Some(instance), Some(state)) = (processes.get(&id), health_checks.get_mut(&id)) else {
+            return;
+        };
+
+        let health = state.record(result, instance.health);
+        if health != instance.health {
+            self.log(&format!(
+                "Process {} at {} is now {:?} (check returned {:?})",
+                id,
+                instance.endpoint(),
+                health,
+                result
+            ));
+            let updated = ServiceInstance {
+                health,
+                ..instance.clone()
+            };
+            if let Err(e) = self.update_processes(processes, vec![updated]) {
+                self.log(&format!("Couldn't persist health of process {}: {}", id, e));
+            }
+        }
+    }
+
+    pub(super) fn drop_health_checks(&self, ids: &[u32]) {
+        let health_checks = &mut *self.health_checks.lock().unwrap();
+        ids.iter().for_each(|id| {
+            health_checks.remove(id);
+        });
+    }
+}
+
+/// Health of a newly registered instance, before any of its checks ran.
+pub(super) fn initial_health(instance: &ServiceInstance) -> HealthStatus {
+    match instance.health_check {
+        Some(_) => HealthStatus::Critical,
+        None => HealthStatus::Passing,
+    }
+}
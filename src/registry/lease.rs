@@ -0,0 +1,130 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use super::Registry;
+use crate::{
+    algorithms::Logger,
+    events::RegistryEvent,
+    service::{endpoints, ServiceInstance},
+};
+
+/// How often the registry looks for lapsed leases.
+pub const LEASE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shorter leases are extended to this, so renewals don't flood the registry.
+pub const MIN_LEASE_TTL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Lease {
+    ttl: Duration,
+    expires_at: Instant,
+}
+
+impl Lease {
+    fn new(ttl: Duration) -> Self {
+        Lease {
+            ttl,
+            expires_at: Instant::now() + ttl,
+        }
+    }
+
+    fn renew(&mut self) {
+        self.expires_at = Instant::now() + self.ttl;
+    }
+
+    fn has_lapsed(&self, now: Instant) -> bool {
+        self.expires_at <= now
+    }
+}
+
+impl Registry {
+    /// Grants `instance` its lease if it asked for one, returning the ttl
+    /// actually granted.
+    pub(super) fn grant_lease(&self, instance: &ServiceInstance) -> Option<Duration> {
+        let ttl = instance.lease_ttl?.max(MIN_LEASE_TTL);
+        self.leases
+            .lock()
+            .unwrap()
+            .insert(instance.instance_id, Lease::new(ttl));
+
+        Some(ttl)
+    }
+
+    pub(super) fn renew_lease(&self, id: u32) -> RegistryEvent {
+        let revision = self.revision();
+        match self.leases.lock().unwrap().get_mut(&id) {
+            Some(lease) => {
+                lease.renew();
+                self.log(&format!("Renewed lease of process {}", id));
+                RegistryEvent::LeaseRenewed {
+                    revision,
+                    ttl: lease.ttl,
+                }
+            }
+            None => {
+                self.log(&format!("Process {} has no lease to renew", id));
+                RegistryEvent::LeaseExpired { revision }
+            }
+        }
+    }
+
+    /// Removes the processes whose lease lapsed, on the leader of a cluster.
+    pub(super) fn expire_leases(&self) {
+        if !self.is_leader() {
+            return;
+        }
+        let processes = &mut *self.processes.lock().unwrap();
+        let paxos_status = &mut *self.paxos_status.lock().unwrap();
+
+        let now = Instant::now();
+        let expired: Vec<u32> = self
+            .leases
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, lease)| lease.has_lapsed(now))
+            .map(|(id, _)| *id)
+            .collect();
+        if expired.is_empty() {
+            return;
+        }
+
+        expired
+            .iter()
+            .filter_map(|id| processes.get(id))
+            .for_each(|instance| {
+                self.log(&format!(
+                    "Lease of process {} at {} lapsed, removing it...",
+                    instance.instance_id,
+                    instance.endpoint()
+                ));
+            });
+        // Processes whose removal couldn't be persisted lapse again next round
+        if let Err(e) = self.remove_processes(processes, &expired, paxos_status) {
+            self.log(&format!("Couldn't persist lapsed leases: {}", e));
+        }
+        self.push_registered_processes(processes.clone());
+    }
+
+    /// Endpoints of the processes the registry probes itself, i.e. those
+    /// without a lease.
+    pub(super) fn probed_endpoints(
+        &self,
+        processes: &HashMap<u32, ServiceInstance>,
+    ) -> HashMap<u32, String> {
+        let leases = self.leases.lock().unwrap();
+        endpoints(processes)
+            .into_iter()
+            .filter(|(id, _)| !leases.contains_key(id))
+            .collect()
+    }
+
+    pub(super) fn drop_leases(&self, ids: &[u32]) {
+        let leases = &mut *self.leases.lock().unwrap();
+        ids.iter().for_each(|id| {
+            leases.remove(id);
+        });
+    }
+}
@@ -1,22 +1,52 @@
 use std::{
     collections::HashMap,
-    io::{ErrorKind, Read},
-    net::{IpAddr, TcpListener},
+    io::ErrorKind,
+    net::{IpAddr, TcpListener, TcpStream},
     sync::{Arc, Mutex},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use chrono::Utc;
+
 use crate::{
-    algorithms::{Logger, PaxosProposer},
+    algorithms::{BroadcastMode, BroadcastOutcome, Logger, PaxosProposer},
+    cluster::{ClusterConfig, Election},
+    codec::CodecKind,
     events::{
-        Event, PaxosAcceptedValue, PaxosAcceptorEvent, PaxosStatus, ProcessEvent, RegistryEvent,
+        common_capabilities, negotiate_version, Capability, Envelope, EnvelopeError, Event,
+        PaxosAcceptedValue, PaxosAcceptorEvent, PaxosStatus, ProcessEvent, RegistryEvent,
+        SUPPORTED_PROTOCOL_VERSIONS,
     },
-    handle_buffer, Broadcast, P2PSend,
+    failure_detector::{FailureDetectorConfig, PhiAccrual, Suspicion},
+    health::{CheckState, HealthCheckPolicy},
+    pool::connection_pool,
+    service::{endpoints, MembershipChange, ServiceInstance},
+    shutdown::{Shutdown, ShutdownHandle, POLL_INTERVAL},
+    storage::{PersistenceConfig, Storage},
+    Broadcast, P2PSend,
 };
 
-type Processes = Arc<Mutex<HashMap<u32, String>>>;
+#[cfg(feature = "async")]
+mod async_run;
+mod health;
+mod lease;
+mod persistence;
+mod replication;
+mod watch;
+
+use health::{initial_health, HEALTH_CHECK_TICK};
+use lease::{Lease, LEASE_CHECK_INTERVAL};
+use persistence::STORAGE_TICK;
+use replication::ELECTION_TICK;
+use watch::{ChangeLog, PendingPush, Watcher};
+
+type Processes = Arc<Mutex<HashMap<u32, ServiceInstance>>>;
 type AMu32 = Arc<Mutex<u32>>;
+type AMu64 = Arc<Mutex<u64>>;
+
+/// How often the registry probes the processes without a lease.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
 
 pub struct Registry {
     last_registered_id: AMu32,
@@ -26,6 +56,27 @@ pub struct Registry {
     accepted_received: AMu32,
     accepted_values_received: Arc<Mutex<Vec<Option<PaxosAcceptedValue>>>>,
     paxos_status: Arc<Mutex<PaxosStatus>>,
+    codec: CodecKind,
+    max_frame_size: usize,
+    shutdown: Arc<Shutdown>,
+    changes: Arc<Mutex<ChangeLog>>,
+    watchers: Arc<Mutex<HashMap<String, Watcher>>>,
+    pending_push: Arc<PendingPush>,
+    last_pushed_revision: AMu64,
+    leases: Arc<Mutex<HashMap<u32, Lease>>>,
+    health_checks: Arc<Mutex<HashMap<u32, CheckState>>>,
+    health_check_policy: HealthCheckPolicy,
+    failure_detector: FailureDetectorConfig,
+    detectors: Arc<Mutex<HashMap<u32, PhiAccrual>>>,
+    suspected_since: Arc<Mutex<HashMap<u32, Instant>>>,
+    persistence: Option<PersistenceConfig>,
+    storage: Arc<Mutex<Option<Storage>>>,
+    cluster: Option<ClusterConfig>,
+    election: Arc<Mutex<Election>>,
+    /// Revision each follower is known to be at, `None` for those due a
+    /// snapshot.
+    followers: Arc<Mutex<HashMap<u32, Option<u64>>>>,
+    pending_replication: Arc<PendingPush>,
 }
 
 impl P2PSend for Registry {}
@@ -33,8 +84,25 @@ impl Broadcast for Registry {}
 impl PaxosProposer for Registry {}
 
 impl Registry {
-    pub fn new() -> Self {
+    /// Without `persistence` the registry starts empty and forgets
+    /// everything when it stops, including the ids it gave: they're only
+    /// unique across restarts with `persistence`. With `cluster` it's one node of a cluster,
+    /// only the elected leader takes writes, followers replicate its table
+    /// and serve lookups and watches. Processes asking for a health check
+    /// `health_check_policy` refuses are rejected. Frames over
+    /// `max_frame_size` bytes are neither sent nor read.
+    pub fn new(
+        codec: CodecKind,
+        max_frame_size: usize,
+        failure_detector: FailureDetectorConfig,
+        persistence: Option<PersistenceConfig>,
+        cluster: Option<ClusterConfig>,
+        health_check_policy: HealthCheckPolicy,
+    ) -> Self {
         let processes = HashMap::new();
+        let election_timeout = cluster
+            .as_ref()
+            .map_or(Duration::ZERO, |cluster| cluster.election_timeout);
         Registry {
             last_registered_id: Arc::new(Mutex::new(0)),
             processes: Arc::new(Mutex::new(processes)),
@@ -43,125 +111,313 @@ impl Registry {
             accepted_received: Arc::new(Mutex::new(0)),
             accepted_values_received: Arc::new(vec![].into()),
             paxos_status: Arc::new(Mutex::new(PaxosStatus::NoConsensus)),
+            codec,
+            max_frame_size,
+            shutdown: Arc::new(Shutdown::default()),
+            changes: Arc::new(Mutex::new(ChangeLog::default())),
+            watchers: Arc::new(Mutex::new(HashMap::new())),
+            pending_push: Arc::new(PendingPush::default()),
+            last_pushed_revision: Arc::new(Mutex::new(0)),
+            leases: Arc::new(Mutex::new(HashMap::new())),
+            health_checks: Arc::new(Mutex::new(HashMap::new())),
+            health_check_policy,
+            failure_detector,
+            detectors: Arc::new(Mutex::new(HashMap::new())),
+            suspected_since: Arc::new(Mutex::new(HashMap::new())),
+            persistence,
+            storage: Arc::new(Mutex::new(None)),
+            cluster,
+            election: Arc::new(Mutex::new(Election::new(election_timeout))),
+            followers: Arc::new(Mutex::new(HashMap::new())),
+            pending_replication: Arc::new(PendingPush::default()),
         }
     }
 
-    pub fn run(&self, addr: &String) -> std::io::Result<()> {
+    /// Runs the registry until it is shut down.
+    pub fn run(&self, addr: &str) -> std::io::Result<()> {
         let listener = TcpListener::bind(addr)?;
-        self.log(&format!("Started registry on {}", addr));
+        self.recover()?;
+        self.serve(listener)
+    }
+
+    /// Runs the registry on a background thread, returning once it listens.
+    pub fn start(self, addr: &str) -> std::io::Result<ShutdownHandle> {
+        let listener = TcpListener::bind(addr)?;
+        self.recover()?;
+        let shutdown = self.shutdown.clone();
+        let thread = thread::spawn(move || self.serve(listener));
 
+        Ok(ShutdownHandle::new(shutdown, thread))
+    }
+
+    fn serve(&self, listener: TcpListener) -> std::io::Result<()> {
+        self.log(&format!("Started registry on {}", listener.local_addr()?));
+
+        listener.set_nonblocking(true)?;
         thread::scope(|s| {
             // Thread to check all processes if alive and broadcast the processes table
-            s.spawn(move || loop {
-                thread::sleep(Duration::from_secs(20));
+            s.spawn(move || {
+                while !self.shutdown.wait_timeout(HEARTBEAT_INTERVAL) {
+                    self.send_heartbeat();
+                    let _ = self.broadcast_registered_processes();
+                    connection_pool().evict_idle();
+                }
+            });
 
-                let paxos_status = self.paxos_status.try_lock();
-                if paxos_status.is_ok() {
-                    let paxos_status = &mut *paxos_status.unwrap();
+            // Thread to start paxos consensus instance
+            s.spawn(move || {
+                while !self.shutdown.wait_timeout(Duration::from_secs(10)) {
+                    // Run the instance without holding the status lock, responses update it
+                    if let Some(acceptors) = self.check_consensus_status() {
+                        self.run_consensus_instance(self.paxos_seq_number, &acceptors);
+                    }
+                }
+            });
 
-                    self.send_heartbeat(paxos_status);
-                    match self.broadcast_registered_processes() {
-                        Ok(_) => {}
-                        Err(_) => {}
-                    };
+            // Thread to remove processes whose lease lapsed
+            s.spawn(move || {
+                while !self.shutdown.wait_timeout(LEASE_CHECK_INTERVAL) {
+                    self.expire_leases();
                 }
             });
 
-            // Thread to start paxos consensus instance
-            s.spawn(move || loop {
-                thread::sleep(Duration::from_secs(10));
-                let paxos_status = self.paxos_status.try_lock();
-                if paxos_status.is_ok() {
-                    let paxos_status = &mut *paxos_status.unwrap();
-                    match paxos_status {
-                        PaxosStatus::NoConsensus => {
-                            self.start_consensus_instance(
-                                self.paxos_seq_number.clone(),
-                                paxos_status,
-                            );
-                        }
-                        paxos_status => {
-                            match paxos_status {
-                                PaxosStatus::ConsensusReached(value) => {
-                                    self.log(&format!(
-                                        "#PAXOS# Consensus is reached, value is {:?}",
-                                        value
-                                    ));
-                                    thread::sleep(Duration::from_secs(5));
-                                }
-                                PaxosStatus::Phase1 => {
-                                    self.log("#PAXOS# Phase 1");
-                                }
-                                PaxosStatus::Phase2 => {
-                                    self.log("#PAXOS# Phase 2");
-                                }
-                                _ => {}
-                            }
-                            drop(paxos_status)
-                        }
+            // Thread to run health checks as they fall due, each on its own thread
+            s.spawn(move || {
+                while !self.shutdown.wait_timeout(HEALTH_CHECK_TICK) {
+                    for (instance, check) in self.due_health_checks() {
+                        s.spawn(move || self.run_health_check(instance, check));
                     }
                 }
             });
 
-            // Listen for incoming events
-            for stream in listener.incoming() {
-                let mut buffer = [0u8; 1000];
+            // Thread to sync and compact the log
+            s.spawn(move || {
+                while !self.shutdown.wait_timeout(STORAGE_TICK) {
+                    self.maintain_storage(false);
+                }
+            });
+
+            // Thread to push membership changes to watchers as they happen
+            s.spawn(move || {
+                while !self.shutdown.is_requested() {
+                    if self.pending_push.take(POLL_INTERVAL) {
+                        self.push_to_watchers();
+                    }
+                }
+            });
 
+            if let Some(cluster) = &self.cluster {
+                // Thread to elect a new leader once the current one goes quiet
                 s.spawn(move || {
-                    let mut stream = stream.unwrap();
-                    let data_size = stream.read(&mut buffer).unwrap();
-                    let peer_addr = stream.peer_addr().unwrap().ip();
-
-                    if data_size > 0 {
-                        let event = handle_buffer(buffer, data_size);
-
-                        match event {
-                            Some(event) => match event {
-                                Event::ProcessEvent(process_event) => {
-                                    self.handle_process_event(peer_addr, process_event);
-                                }
-                                Event::PaxosAcceptorEvent(acceptor_event) => {
-                                    self.handle_acceptor_event(acceptor_event);
-                                }
-                                Event::RegistryEvent(_) | Event::PaxosProposerEvent(_) => {
-                                    self.log("Another registry running ?!");
-                                }
-                            },
-                            None => {}
-                        };
+                    while !self.shutdown.wait_timeout(ELECTION_TICK) {
+                        self.run_election();
                     }
                 });
+
+                // Thread to replicate changes to followers while leading
+                s.spawn(move || {
+                    while !self.shutdown.is_requested() {
+                        self.pending_replication.take(cluster.heartbeat_interval);
+                        self.replicate();
+                    }
+                });
+            }
+
+            // Listen for incoming events
+            while !self.shutdown.is_requested() {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        s.spawn(move || self.handle_connection(stream));
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        self.shutdown.wait_timeout(POLL_INTERVAL);
+                    }
+                    Err(e) => self.log(&format!("Couldn't accept connection: {}", e)),
+                }
             }
         });
+
+        self.maintain_storage(true);
+        self.log("Stopped");
         Ok(())
     }
 
-    fn handle_process_event(&self, process_addr: IpAddr, process_event: ProcessEvent) {
+    fn handle_connection(&self, stream: TcpStream) {
+        let Ok(peer_addr) = stream.peer_addr().map(|peer_addr| peer_addr.ip()) else {
+            return;
+        };
+        Registry::serve_frames(
+            stream,
+            &self.shutdown,
+            self,
+            self.codec,
+            self.max_frame_size,
+            |envelope| self.respond(peer_addr, envelope),
+            |e| self.reject_envelope(peer_addr, e),
+        );
+    }
+
+    /// Answers an envelope received from `peer_addr` once it was handled,
+    /// forwarding deregistrations sent to a follower to the leader, and
+    /// holding registrations back until the cluster replicated them.
+    fn respond(&self, peer_addr: IpAddr, envelope: Envelope) -> Option<Envelope> {
+        if self.must_forward(&envelope) {
+            self.forward_to_leader(&envelope);
+            return None;
+        }
+
+        let response = self.handle_envelope(peer_addr, envelope)?;
+        Some(self.acknowledge(response))
+    }
+
+    /// Dispatches an envelope received from `peer_addr`, returning the
+    /// response to send back if it was a request.
+    fn handle_envelope(&self, peer_addr: IpAddr, envelope: Envelope) -> Option<Envelope> {
+        if matches!(&envelope.event, Event::ProcessEvent(process_event) if process_event.is_write())
+            && !self.is_leader()
+        {
+            return Some(self.redirect_to_leader(envelope));
+        }
+
+        match envelope.event {
+            Event::ProcessEvent(process_event) => self
+                .handle_process_event(peer_addr, process_event)
+                .map(|response| Envelope::reply_to(envelope.correlation_id, None, response)),
+            Event::PaxosAcceptorEvent(_) => {
+                self.log("#PAXOS# Acceptor event outside of a request");
+                None
+            }
+            Event::ClusterEvent(cluster_event) => {
+                self.handle_cluster_event(cluster_event).map(|response| {
+                    Envelope::reply_to(envelope.correlation_id, self.node_id(), response)
+                })
+            }
+            Event::RegistryEvent(_) | Event::PaxosProposerEvent(_) => {
+                self.log("Another registry running ?!");
+                None
+            }
+        }
+    }
+
+    fn handle_process_event(
+        &self,
+        process_addr: IpAddr,
+        process_event: ProcessEvent,
+    ) -> Option<RegistryEvent> {
         let processes = &mut *(self.processes.lock().unwrap());
         let last_registered_id = &mut *(self.last_registered_id).lock().unwrap();
         let paxos_status = &mut *(self.paxos_status).lock().unwrap();
         match process_event {
-            ProcessEvent::ConnectOnPort { port } => {
+            ProcessEvent::ConnectOnPort {
+                port,
+                protocol_versions,
+                capabilities,
+                instance,
+            } => {
                 self.log(&format!("Received CONNECT from {}:{}", process_addr, port));
-                self.register_process(
-                    format!("{}:{}", process_addr, port),
-                    processes,
-                    last_registered_id,
-                    paxos_status,
-                );
+                let addr = format!("{}:{}", process_addr, port);
+                let instance = ServiceInstance {
+                    address: process_addr.to_string(),
+                    port,
+                    ..*instance
+                };
+
+                let refusal = instance
+                    .health_check
+                    .as_ref()
+                    .and_then(|check| self.health_check_policy.refusal(check));
+                if let Some(reason) = refusal {
+                    return Some(self.reject_process(addr, reason));
+                }
+
+                Some(match negotiate_version(&protocol_versions) {
+                    Some(protocol_version) => self.register_process(
+                        instance,
+                        processes,
+                        last_registered_id,
+                        paxos_status,
+                        protocol_version,
+                        common_capabilities(&capabilities),
+                    ),
+                    None => self.reject_process(
+                        addr,
+                        format!(
+                            "no common protocol version, offered {:?}",
+                            protocol_versions
+                        ),
+                    ),
+                })
             }
             ProcessEvent::Message { from, msg } => {
                 self.log(&format!("Received message from process {}: {}", from, msg));
+                None
+            }
+            ProcessEvent::Deregister { id, instance_uuid } => {
+                self.log(&format!(
+                    "Received DEREGISTER of process {} from {}",
+                    id, process_addr
+                ));
+                let authorized = processes.get(&id).map(|instance| {
+                    instance.address == process_addr.to_string()
+                        || (instance_uuid.is_some() && instance_uuid == instance.instance_uuid)
+                });
+                match authorized {
+                    Some(false) => self.log(&format!(
+                        "Ignoring DEREGISTER of process {} from {}, which isn't it",
+                        id, process_addr
+                    )),
+                    _ => self.deregister_process(id, processes, paxos_status),
+                }
+                None
+            }
+            ProcessEvent::RenewLease { id } => Some(self.renew_lease(id)),
+            ProcessEvent::Lookup { query } => {
+                let instances = query.filter(processes.values());
+                self.log(&format!(
+                    "Received LOOKUP {:?} from {}, {} matching instances",
+                    query,
+                    process_addr,
+                    instances.len()
+                ));
+                Some(RegistryEvent::LookupResult {
+                    revision: self.revision(),
+                    instances,
+                })
+            }
+            ProcessEvent::Watch {
+                service_name,
+                port,
+                since,
+            } => {
+                let addr = format!("{}:{}", process_addr, port);
+                self.add_watcher(addr, service_name, since);
+                None
             }
         }
     }
 
-    fn handle_acceptor_event(&self, acceptor_event: PaxosAcceptorEvent) {
+    fn handle_acceptor_response(&self, response: Envelope) -> Option<(u32, PaxosAcceptedValue)> {
+        match response.event {
+            Event::PaxosAcceptorEvent(acceptor_event) => self.handle_acceptor_event(acceptor_event),
+            _ => {
+                self.log("#PAXOS# Unexpected response from acceptor");
+                None
+            }
+        }
+    }
+
+    /// Returns the value to request acceptance for once a majority promised.
+    fn handle_acceptor_event(
+        &self,
+        acceptor_event: PaxosAcceptorEvent,
+    ) -> Option<(u32, PaxosAcceptedValue)> {
+        // Released before the Paxos state is locked, which comes after the
+        // table everywhere else
+        let majority = self.processes.lock().unwrap().len() / 2 + 1;
         let promises_received = &mut *(self.promises_received).lock().unwrap();
         let accepted_received = &mut *(self.accepted_received).lock().unwrap();
         let accepted_values_received = &mut *(self.accepted_values_received).lock().unwrap();
         let paxos_status = &mut *(self.paxos_status).lock().unwrap();
-        let acceptors = &mut *self.processes.lock().unwrap();
         let self_seq_number = self.paxos_seq_number;
 
         match (acceptor_event, &paxos_status) {
@@ -171,39 +427,34 @@ impl Registry {
                     seq_number, value
                 ));
 
-                let _ = std::mem::replace(promises_received, *promises_received + 1);
-                let majority = acceptors.len() / 2 + 1;
+                *promises_received += 1;
                 accepted_values_received.push(value);
 
                 // - Broadcast to acceptors, only if we recieved majority of promise
                 if *promises_received >= majority as u32 {
                     let av_with_max_sn = accepted_values_received
                         .iter()
-                        .filter(|&av| av.is_some())
-                        .map(|av| av.as_ref().unwrap())
+                        .flatten()
                         .max_by(|av1, av2| av1.seq_number.cmp(&av2.seq_number));
 
                     // If some AVs are not None, take the value with the biggest Sn
-                    if av_with_max_sn.is_some() {
-                        Registry::request_accept(
-                            seq_number,
-                            av_with_max_sn.unwrap().clone(),
-                            acceptors,
-                        );
+                    let accept_request = if let Some(&av) = av_with_max_sn {
+                        (seq_number, av)
                     } else {
                         // If all AV are None, propose a value
                         let av = PaxosAcceptedValue {
-                            seq_number: self_seq_number.clone(),
+                            seq_number: self_seq_number,
                             value: rand::Rng::gen_range(&mut rand::thread_rng(), 100..1000),
                         };
 
-                        Registry::request_accept(self_seq_number, av, acceptors);
-                    }
+                        (self_seq_number, av)
+                    };
                     self.log("#PAXOS# Moving to Phase2");
-                    let _ = std::mem::replace(paxos_status, PaxosStatus::Phase2);
-                    let _ = std::mem::replace(promises_received, 0);
-                    drop(paxos_status);
+                    *paxos_status = PaxosStatus::Phase2;
+                    *promises_received = 0;
                     accepted_values_received.clear();
+
+                    return Some(accept_request);
                 }
             }
             (PaxosAcceptorEvent::Accepted { seq_number, value }, PaxosStatus::Phase2) => {
@@ -212,20 +463,15 @@ impl Registry {
                     seq_number, value
                 ));
 
-                let _ = std::mem::replace(accepted_received, *accepted_received + 1);
-                let majority = acceptors.len() / 2 + 1;
+                *accepted_received += 1;
                 if *accepted_received >= majority as u32 {
                     self.log(&format!(
                         "#PAXOS# Consensus reached with value {:?}",
                         value.unwrap()
                     ));
-                    let _ = std::mem::replace(
-                        paxos_status,
-                        PaxosStatus::ConsensusReached(value.unwrap()),
-                    );
-                    drop(paxos_status);
-
-                    let _ = std::mem::replace(accepted_received, 0);
+                    *paxos_status = PaxosStatus::ConsensusReached(value.unwrap());
+                    *accepted_received = 0;
+                    self.persist_consensus(value.unwrap());
                 }
             }
             (PaxosAcceptorEvent::KO, _) => {
@@ -243,110 +489,681 @@ impl Registry {
                 self.log("#PAXOS# Unknown");
             }
         }
+        None
     }
 
     fn register_process(
         &self,
-        addr: String,
-        processes: &mut HashMap<u32, String>,
+        instance: ServiceInstance,
+        processes: &mut HashMap<u32, ServiceInstance>,
         last_registered_id: &mut u32,
         paxos_status: &mut PaxosStatus,
-    ) {
-        let next_process_id = *last_registered_id + 1;
+        protocol_version: u16,
+        capabilities: Vec<Capability>,
+    ) -> RegistryEvent {
+        let known_instance = instance
+            .instance_uuid
+            .as_ref()
+            .and_then(|uuid| {
+                processes
+                    .values()
+                    .find(|known| known.instance_uuid.as_ref() == Some(uuid))
+            })
+            .cloned();
+        // Only a process the registry knows by its uuid gets its id back,
+        // any other claimed id may belong to someone else
+        let process_id = match &known_instance {
+            Some(known_instance) => known_instance.instance_id,
+            None => match last_registered_id.checked_add(1) {
+                Some(process_id) => process_id,
+                None => {
+                    return self
+                        .reject_process(instance.endpoint(), "out of process ids".to_owned())
+                }
+            },
+        };
 
-        processes.insert(next_process_id, addr.clone());
-        self.log(&format!(
-            "Registered process {} at id {}",
-            &addr, next_process_id
-        ));
+        let mut instance = ServiceInstance {
+            instance_id: process_id,
+            registered_at: known_instance
+                .as_ref()
+                .map_or_else(Utc::now, |known_instance| known_instance.registered_at),
+            ..instance
+        };
+        instance.lease_ttl = self.grant_lease(&instance);
+        instance.health_check = self.schedule_health_check(&instance);
+        instance.health = initial_health(&instance);
+        instance.suspicion = Suspicion::Alive;
+        self.track_liveness(&instance);
+        self.suspected_since.lock().unwrap().remove(&process_id);
 
-        let _ = std::mem::replace(paxos_status, PaxosStatus::NoConsensus);
-        drop(paxos_status);
+        // Membership only changes for processes the registry didn't know
+        let change = match &known_instance {
+            Some(_) => MembershipChange::Updated(instance.clone()),
+            None => MembershipChange::Added(instance.clone()),
+        };
+        // A registration is only acknowledged once it's durable
+        if let Err(e) = self.record_changes([change]) {
+            match &known_instance {
+                Some(known_instance) => self.start_tracking(known_instance),
+                None => self.stop_tracking(&[process_id]),
+            }
+            return self.reject_process(
+                instance.endpoint(),
+                format!("couldn't persist the registration: {}", e),
+            );
+        }
 
-        *last_registered_id = next_process_id;
+        self.log(&format!(
+            "{} process {} ({} {}) at id {} using protocol version {}",
+            if known_instance.is_some() {
+                "Re-registered"
+            } else {
+                "Registered"
+            },
+            instance.endpoint(),
+            instance.service_name,
+            instance.version,
+            process_id,
+            protocol_version
+        ));
+        if known_instance.is_none() {
+            *paxos_status = PaxosStatus::NoConsensus;
+        }
+        processes.insert(process_id, instance);
+        *last_registered_id = (*last_registered_id).max(process_id);
 
-        let registry_event = &RegistryEvent::Registered {
-            given_id: *last_registered_id,
+        RegistryEvent::Registered {
+            given_id: process_id,
             registered_processes: processes.clone(),
+            revision: self.revision(),
+            protocol_version,
+            capabilities,
         }
-        .as_bytes_vec()[..];
+    }
 
-        match Registry::send(&addr, registry_event) {
-            Ok(_) => {}
-            _ => {
-                self.log(&format!("Couldn't reach process {}", &addr));
+    fn deregister_process(
+        &self,
+        id: u32,
+        processes: &mut HashMap<u32, ServiceInstance>,
+        paxos_status: &mut PaxosStatus,
+    ) {
+        let Some(endpoint) = processes.get(&id).map(ServiceInstance::endpoint) else {
+            self.log(&format!("Process {} isn't registered, ignoring", id));
+            return;
+        };
+        match self.remove_processes(processes, &[id], paxos_status) {
+            Ok(()) => {
+                self.log(&format!("Deregistered process {} at id {}", endpoint, id));
+                self.push_registered_processes(processes.clone());
             }
+            Err(e) => self.log(&format!(
+                "Couldn't deregister process {} at id {}: {}",
+                endpoint, id, e
+            )),
+        }
+    }
+
+    /// Rejects the request of a peer speaking an envelope version this
+    /// build can't decode, in the peer's codec, so it learns which versions
+    /// the registry supports instead of timing out.
+    fn reject_envelope(
+        &self,
+        peer_addr: IpAddr,
+        error: &EnvelopeError,
+    ) -> Option<(Envelope, CodecKind)> {
+        let EnvelopeError::UnsupportedVersion {
+            correlation_id,
+            codec,
+            ..
+        } = error
+        else {
+            return None;
         };
+        let response = self.reject_process(peer_addr.to_string(), error.to_string());
+
+        Some((Envelope::reply_to(*correlation_id, None, response), *codec))
     }
 
-    fn broadcast_registered_processes(&self) -> std::io::Result<usize> {
-        let processes = self.processes.try_lock();
-        if processes.is_ok() {
-            let processes = &mut processes.unwrap();
-            if processes.len() > 0 {
-                self.log("Sending updated table of processes");
-                let registry_event =
-                    &RegistryEvent::UpdateRegisteredProcesses(processes.clone()).as_bytes_vec()[..];
+    fn reject_process(&self, addr: String, reason: String) -> RegistryEvent {
+        self.log(&format!("Rejecting process {}: {}", &addr, reason));
 
-                Registry::broadcast_to_all(&processes, registry_event)
-            } else {
-                drop(processes);
-                Err(ErrorKind::Other.into())
+        RegistryEvent::Rejected {
+            reason,
+            supported_versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+            revision: self.revision(),
+        }
+    }
+
+    fn broadcast_registered_processes(&self) -> std::io::Result<BroadcastOutcome<usize>> {
+        let (processes, registry_event) = self.registered_processes_update()?;
+
+        let outcome = Registry::broadcast_to_all(&processes, &registry_event, self.max_frame_size);
+        self.log_failed_pushes(&outcome);
+
+        Ok(outcome)
+    }
+
+    /// Pushes `processes` to its members in the background, so the
+    /// connection that changed the table isn't held up by slow processes.
+    fn push_registered_processes(&self, processes: HashMap<u32, ServiceInstance>) {
+        if processes.is_empty() {
+            return;
+        }
+
+        let endpoints = endpoints(&processes);
+        let registry_event = Envelope::new(
+            None,
+            RegistryEvent::UpdateRegisteredProcesses {
+                revision: self.revision(),
+                processes,
+            },
+        )
+        .as_bytes_vec(self.codec);
+        let max_frame_size = self.max_frame_size;
+        thread::spawn(move || {
+            Registry::broadcast_to_all(&endpoints, &registry_event, max_frame_size)
+        });
+    }
+
+    fn registered_processes_update(&self) -> std::io::Result<(HashMap<u32, String>, Vec<u8>)> {
+        // Processes only hear from the leader
+        if !self.is_leader() {
+            return Err(ErrorKind::Other.into());
+        }
+
+        // Push a snapshot so registrations aren't blocked on slow processes
+        let processes = match self.processes.try_lock() {
+            Ok(processes) if !processes.is_empty() => processes,
+            _ => return Err(ErrorKind::Other.into()),
+        };
+
+        // Watchers get every change as it happens, the full table only goes
+        // out again once it changed
+        let revision = self.revision();
+        let last_pushed_revision = &mut *self.last_pushed_revision.lock().unwrap();
+        if revision == *last_pushed_revision {
+            return Err(ErrorKind::Other.into());
+        }
+        *last_pushed_revision = revision;
+        let processes = processes.clone();
+
+        self.log("Sending updated table of processes");
+        let endpoints = endpoints(&processes);
+        let registry_event = Envelope::new(
+            None,
+            RegistryEvent::UpdateRegisteredProcesses {
+                revision,
+                processes,
+            },
+        )
+        .as_bytes_vec(self.codec);
+
+        Ok((endpoints, registry_event))
+    }
+
+    fn log_failed_pushes(&self, outcome: &BroadcastOutcome<usize>) {
+        outcome
+            .iter()
+            .filter_map(|(id, result)| result.as_ref().err().map(|e| (id, e)))
+            .for_each(|(id, e)| {
+                self.log(&format!("Couldn't push table to process {}: {}", id, e));
+            });
+    }
+
+    /// Resumes the lease, failure detector and health checks of a process
+    /// this node didn't register itself.
+    fn start_tracking(&self, instance: &ServiceInstance) {
+        self.grant_lease(instance);
+        self.schedule_health_check(instance);
+        self.track_liveness(instance);
+    }
+
+    fn stop_tracking(&self, ids: &[u32]) {
+        self.drop_leases(ids);
+        self.drop_health_checks(ids);
+        let detectors = &mut *self.detectors.lock().unwrap();
+        let suspected_since = &mut *self.suspected_since.lock().unwrap();
+        ids.iter().for_each(|id| {
+            detectors.remove(id);
+            suspected_since.remove(id);
+        });
+    }
+
+    /// Starts a failure detector for `instance`, unless it renews a lease.
+    fn track_liveness(&self, instance: &ServiceInstance) {
+        if instance.lease_ttl.is_none() {
+            self.detectors.lock().unwrap().insert(
+                instance.instance_id,
+                PhiAccrual::new(HEARTBEAT_INTERVAL, &self.failure_detector),
+            );
+        }
+    }
+
+    /// Probes every process concurrently, without holding the table while
+    /// waiting on them. The sweep, retries included, takes at most
+    /// `BROADCAST_DEADLINE`.
+    fn send_heartbeat(&self) {
+        if let Some(processes) = self.heartbeat_targets() {
+            let deadline = Instant::now() + Registry::BROADCAST_DEADLINE;
+            let mut outcome = Registry::broadcast_to_all(&processes, &[], self.max_frame_size);
+            for _ in 0..self.failure_detector.probe_retries {
+                let failed = failed_probes(&processes, &outcome);
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if failed.is_empty() || remaining.is_zero() {
+                    break;
+                }
+                outcome.extend(Registry::broadcast_with(
+                    &failed,
+                    &[],
+                    self.max_frame_size,
+                    BroadcastMode::All,
+                    remaining,
+                ));
             }
-        } else {
-            Err(ErrorKind::Other.into())
+            self.handle_heartbeat_outcome(&processes, outcome);
         }
     }
 
-    fn send_heartbeat(&self, paxos_status: &mut PaxosStatus) {
-        let processes = self.processes.try_lock();
-        if processes.is_ok() {
-            let processes = &mut processes.unwrap();
+    /// Processes the registry probes itself, `None` if there are none or
+    /// this node follows another.
+    fn heartbeat_targets(&self) -> Option<HashMap<u32, String>> {
+        if !self.is_leader() {
+            return None;
+        }
+        let processes = self.processes.try_lock().ok()?;
+        let probed_processes = self.probed_endpoints(&processes);
+        if probed_processes.is_empty() {
+            return None;
+        }
 
-            if processes.len() > 0 {
-                self.log("Sending heartbeat...");
-                let mut dead_processes = vec![];
+        self.log("Sending heartbeat...");
+        Some(probed_processes)
+    }
 
-                processes.iter().for_each(|(id, addr)| {
-                    if Registry::process_is_alive(addr.to_owned()) {
-                        self.log(&format!("Process at {} is alive", addr));
-                    } else {
-                        self.log(&format!("Process at {} is dead, removing it...", addr));
-                        dead_processes.push(id.clone());
+    /// Feeds the sweep to the failure detectors, then updates every process
+    /// whose suspicion changed and removes the dead ones at once.
+    fn handle_heartbeat_outcome(
+        &self,
+        processes: &HashMap<u32, String>,
+        outcome: BroadcastOutcome<usize>,
+    ) {
+        let suspicions: Vec<(u32, Suspicion)> = {
+            let detectors = &mut *self.detectors.lock().unwrap();
+            outcome
+                .into_iter()
+                .filter_map(|(id, result)| {
+                    // The process may have left during the sweep
+                    let detector = detectors.get_mut(&id)?;
+                    if result.is_ok() {
+                        detector.heartbeat();
+                        self.log(&format!("Process at {} is alive", processes[&id]));
+                        return Some((id, Suspicion::Alive));
                     }
-                });
 
-                if !dead_processes.is_empty() {
-                    dead_processes.iter().for_each(|&id| {
-                        &processes.remove(&id);
+                    let phi = detector.phi();
+                    self.log(&format!(
+                        "Process at {} missed a heartbeat (phi {:.2})",
+                        processes[&id], phi
+                    ));
+                    Some((id, self.failure_detector.assess(phi)))
+                })
+                .collect()
+        };
+
+        let processes = &mut *self.processes.lock().unwrap();
+        let paxos_status = &mut *self.paxos_status.lock().unwrap();
+        let mut suspected_since = self.suspected_since.lock().unwrap();
+
+        let mut changes = vec![];
+        let mut dead_processes = vec![];
+        for (id, suspicion) in suspicions {
+            let Some(instance) = processes.get(&id) else {
+                continue;
+            };
+
+            if suspicion == Suspicion::Alive {
+                suspected_since.remove(&id);
+                if instance.suspicion != Suspicion::Alive {
+                    self.log(&format!(
+                        "Process at {} is no longer suspected",
+                        instance.endpoint()
+                    ));
+                    changes.push(ServiceInstance {
+                        suspicion: Suspicion::Alive,
+                        ..instance.clone()
                     });
-                    let _ = std::mem::replace(paxos_status, PaxosStatus::NoConsensus);
                 }
+                continue;
+            }
+
+            let suspected_for = suspected_since
+                .entry(id)
+                .or_insert_with(Instant::now)
+                .elapsed();
+            if instance.suspicion == Suspicion::Alive {
+                self.log(&format!(
+                    "Process at {} is suspected dead",
+                    instance.endpoint()
+                ));
+                changes.push(ServiceInstance {
+                    suspicion: Suspicion::Suspect,
+                    ..instance.clone()
+                });
+            }
+            if suspicion == Suspicion::Dead
+                && suspected_for >= self.failure_detector.suspect_grace_period
+            {
+                self.log(&format!(
+                    "Process at {} is dead, removing it...",
+                    instance.endpoint()
+                ));
+                dead_processes.push(id);
+            }
+        }
+        // Removing the dead locks it again
+        drop(suspected_since);
+
+        // What couldn't be persisted is looked at again on the next round
+        if let Err(e) = self
+            .update_processes(processes, changes)
+            .and_then(|()| self.remove_processes(processes, &dead_processes, paxos_status))
+        {
+            self.log(&format!("Couldn't persist suspicions: {}", e));
+        }
+    }
+
+    /// Replaces each instance in `processes` once the update is logged,
+    /// stopping at the first that couldn't be.
+    fn update_processes(
+        &self,
+        processes: &mut HashMap<u32, ServiceInstance>,
+        updated: Vec<ServiceInstance>,
+    ) -> std::io::Result<()> {
+        updated.into_iter().try_for_each(|instance| {
+            self.record_changes([MembershipChange::Updated(instance.clone())])?;
+            processes.insert(instance.instance_id, instance);
+            Ok(())
+        })
+    }
+
+    /// Removes each process once its removal is logged, stopping at the
+    /// first that couldn't be: it and the rest stay registered.
+    fn remove_processes(
+        &self,
+        processes: &mut HashMap<u32, ServiceInstance>,
+        dead_processes: &[u32],
+        paxos_status: &mut PaxosStatus,
+    ) -> std::io::Result<()> {
+        let mut removed = vec![];
+        let recorded = dead_processes.iter().try_for_each(|id| {
+            if let Some(instance) = processes.get(id) {
+                self.record_changes([MembershipChange::Removed(instance.clone())])?;
+                removed.extend(processes.remove(id));
             }
+            Ok(())
+        });
+
+        if !removed.is_empty() {
+            self.remove_watchers(removed.iter().map(ServiceInstance::endpoint));
+            let ids: Vec<u32> = removed
+                .iter()
+                .map(|instance| instance.instance_id)
+                .collect();
+            self.stop_tracking(&ids);
+            connection_pool().remove_peers(removed.iter().map(ServiceInstance::endpoint));
+            *paxos_status = PaxosStatus::NoConsensus;
         }
+        recorded
     }
 
-    fn start_consensus_instance(&self, seq_number: u32, paxos_status: &mut PaxosStatus) {
-        let processes = self.processes.try_lock();
-        if processes.is_ok() {
-            let processes = &processes.unwrap();
+    /// Logs where the current consensus instance stands, and returns the
+    /// acceptors to send prepares to when a new instance should start.
+    fn check_consensus_status(&self) -> Option<HashMap<u32, String>> {
+        if !self.is_leader() {
+            return None;
+        }
+        let mut paxos_status = self.paxos_status.try_lock().ok()?;
+        match &*paxos_status {
+            PaxosStatus::NoConsensus => self.start_consensus_instance(&mut paxos_status),
+            PaxosStatus::ConsensusReached(value) => {
+                self.log(&format!(
+                    "#PAXOS# Consensus is reached, value is {:?}",
+                    value
+                ));
+                None
+            }
+            PaxosStatus::Phase1 => {
+                self.log("#PAXOS# Phase 1");
+                None
+            }
+            PaxosStatus::Phase2 => {
+                self.log("#PAXOS# Phase 2");
+                None
+            }
+        }
+    }
+
+    /// Moves to phase 1 and returns the acceptors to send prepares to.
+    fn start_consensus_instance(
+        &self,
+        paxos_status: &mut PaxosStatus,
+    ) -> Option<HashMap<u32, String>> {
+        if let Ok(processes) = self.processes.try_lock() {
             if processes.len() > 2 {
                 self.log("#PAXOS# Starting consensus instance...");
-                let _ = std::mem::replace(paxos_status, PaxosStatus::Phase1);
-                Registry::prepare(seq_number, processes);
+                *paxos_status = PaxosStatus::Phase1;
+                return Some(endpoints(&processes));
             } else {
                 self.log("#PAXOS# Not enough alive processes to start a consensus instance");
-                drop(processes);
             }
         }
+        None
+    }
+
+    fn run_consensus_instance(&self, seq_number: u32, acceptors: &HashMap<u32, String>) {
+        let mut accept_request = None;
+        for response in Registry::prepare(self.codec, self.max_frame_size, seq_number, acceptors)
+            .into_values()
+            .flatten()
+        {
+            if let Some(request) = self.handle_acceptor_response(response) {
+                accept_request = Some(request);
+            }
+        }
+
+        if let Some((seq_number, value)) = accept_request {
+            for response in Registry::request_accept(
+                self.codec,
+                self.max_frame_size,
+                seq_number,
+                value,
+                acceptors,
+            )
+            .into_values()
+            .flatten()
+            {
+                self.handle_acceptor_response(response);
+            }
+        }
+
+        self.finish_consensus_instance();
+    }
+
+    /// Not enough acceptors answered, start over with a new instance.
+    fn finish_consensus_instance(&self) {
+        let paxos_status = &mut *self.paxos_status.lock().unwrap();
+        if matches!(paxos_status, PaxosStatus::Phase1 | PaxosStatus::Phase2) {
+            self.log("#PAXOS# No majority reached, resetting");
+            *paxos_status = PaxosStatus::NoConsensus;
+        }
     }
 }
 
+/// Processes whose probe failed, to be tried again.
+fn failed_probes(
+    processes: &HashMap<u32, String>,
+    outcome: &BroadcastOutcome<usize>,
+) -> HashMap<u32, String> {
+    outcome
+        .iter()
+        .filter(|(_, result)| result.is_err())
+        .map(|(id, _)| (*id, processes[id].clone()))
+        .collect()
+}
+
 impl Logger for Registry {
     fn what_is_self(&self) -> String {
         "Registry".to_string()
     }
     fn what_is_id(&self) -> Option<u32> {
-        None
+        self.node_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        io::{Error, ErrorKind},
+        net::Ipv4Addr,
+        process,
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::framing::DEFAULT_MAX_FRAME_SIZE;
+    use crate::storage::FsyncPolicy;
+
+    fn connect_event(port: u32) -> ProcessEvent {
+        ProcessEvent::ConnectOnPort {
+            port,
+            protocol_versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+            capabilities: vec![],
+            instance: Box::new(ServiceInstance::new("test", "1")),
+        }
+    }
+
+    fn given_id(response: Option<RegistryEvent>) -> u32 {
+        match response {
+            Some(RegistryEvent::Registered { given_id, .. }) => given_id,
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    #[test]
+    fn registers_after_removing_a_dead_process() {
+        // Any missed heartbeat is deadly right away
+        let failure_detector = FailureDetectorConfig {
+            suspect_threshold: 0.0,
+            dead_threshold: 0.0,
+            suspect_grace_period: Duration::ZERO,
+            ..FailureDetectorConfig::default()
+        };
+        let registry = Registry::new(
+            CodecKind::default(),
+            DEFAULT_MAX_FRAME_SIZE,
+            failure_detector,
+            None,
+            None,
+            HealthCheckPolicy::default(),
+        );
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let dead_id = given_id(registry.handle_process_event(localhost, connect_event(1)));
+            registry.handle_heartbeat_outcome(
+                &HashMap::from([(dead_id, "127.0.0.1:1".to_owned())]),
+                HashMap::from([(dead_id, Err(Error::from(ErrorKind::ConnectionRefused)))]),
+            );
+            let removed = !registry.processes.lock().unwrap().contains_key(&dead_id);
+
+            let new_id = given_id(registry.handle_process_event(localhost, connect_event(2)));
+            let _ = sender.send((dead_id, removed, new_id));
+        });
+
+        let (dead_id, removed, new_id) = receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("registry deadlocked");
+        assert!(removed);
+        assert_ne!(new_id, dead_id);
+    }
+
+    #[test]
+    fn recovers_processes_and_ids_after_a_restart() {
+        let data_dir = env::temp_dir().join(format!("processes-registry-{}", process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        let persistent_registry = || {
+            Registry::new(
+                CodecKind::default(),
+                DEFAULT_MAX_FRAME_SIZE,
+                FailureDetectorConfig::default(),
+                Some(PersistenceConfig {
+                    data_dir: data_dir.clone(),
+                    fsync: FsyncPolicy::Always,
+                }),
+                None,
+                HealthCheckPolicy::default(),
+            )
+        };
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let registry = persistent_registry();
+        registry.recover().unwrap();
+        let first_id = given_id(registry.handle_process_event(localhost, connect_event(1)));
+        registry.maintain_storage(true);
+        let second_id = given_id(registry.handle_process_event(localhost, connect_event(2)));
+        drop(registry);
+
+        // One process comes back from the snapshot, the other from the log
+        let registry = persistent_registry();
+        registry.recover().unwrap();
+        let recovered = registry.processes.lock().unwrap().clone();
+        assert_eq!(recovered.len(), 2);
+        assert!(recovered.contains_key(&first_id) && recovered.contains_key(&second_id));
+
+        let third_id = given_id(registry.handle_process_event(localhost, connect_event(3)));
+        assert!(third_id > second_id);
+    }
+
+    #[test]
+    fn only_deregisters_a_process_on_its_own_behalf() {
+        let registry = Registry::new(
+            CodecKind::default(),
+            DEFAULT_MAX_FRAME_SIZE,
+            FailureDetectorConfig::default(),
+            None,
+            None,
+            HealthCheckPolicy::default(),
+        );
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let other_peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let instance = ServiceInstance {
+            instance_uuid: Some("uuid".to_owned()),
+            ..ServiceInstance::new("test", "1")
+        };
+        let id = given_id(registry.handle_process_event(
+            localhost,
+            ProcessEvent::ConnectOnPort {
+                port: 1,
+                protocol_versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+                capabilities: vec![],
+                instance: Box::new(instance),
+            },
+        ));
+        let deregister = |instance_uuid: Option<&str>| ProcessEvent::Deregister {
+            id,
+            instance_uuid: instance_uuid.map(str::to_owned),
+        };
+        let is_registered = || registry.processes.lock().unwrap().contains_key(&id);
+
+        registry.handle_process_event(other_peer, deregister(None));
+        registry.handle_process_event(other_peer, deregister(Some("other uuid")));
+        assert!(is_registered());
+
+        // As forwarded by a follower
+        registry.handle_process_event(other_peer, deregister(Some("uuid")));
+        assert!(!is_registered());
     }
 }
@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use super::{watch::ChangeLog, Registry};
+use crate::{
+    algorithms::Logger,
+    events::{PaxosAcceptedValue, PaxosStatus},
+    service::MembershipChange,
+    storage::{LogEntry, Snapshot, Storage},
+};
+
+/// How often the registry syncs and compacts its log.
+pub const STORAGE_TICK: Duration = Duration::from_secs(1);
+
+impl Registry {
+    /// Restores the state saved in the data directory, if the registry has
+    /// one. Must be called before the registry serves anything.
+    pub(super) fn recover(&self) -> std::io::Result<()> {
+        let Some(config) = self.persistence.clone() else {
+            return Ok(());
+        };
+        let data_dir = config.data_dir.clone();
+        let (storage, snapshot, entries) = Storage::open(config, self.codec)?;
+
+        let election = &mut *self.election.lock().unwrap();
+        let processes = &mut *self.processes.lock().unwrap();
+        let last_registered_id = &mut *self.last_registered_id.lock().unwrap();
+        let paxos_status = &mut *self.paxos_status.lock().unwrap();
+        let change_log = &mut *self.changes.lock().unwrap();
+
+        *processes = snapshot.processes;
+        *last_registered_id = snapshot.last_registered_id;
+        *paxos_status = snapshot
+            .consensus
+            .map_or(PaxosStatus::NoConsensus, PaxosStatus::ConsensusReached);
+        *change_log = ChangeLog::starting_at(snapshot.revision, snapshot.revision_term);
+        election.restore(snapshot.term, snapshot.voted_for);
+
+        for entry in entries {
+            match entry {
+                LogEntry::Membership(delta) => {
+                    if let MembershipChange::Added(instance) = &delta.change {
+                        *last_registered_id = (*last_registered_id).max(instance.instance_id);
+                    }
+                    if !matches!(delta.change, MembershipChange::Updated(_)) {
+                        *paxos_status = PaxosStatus::NoConsensus;
+                    }
+                    delta.change.apply(processes);
+                    change_log.append(*delta);
+                }
+                LogEntry::Consensus { value, .. } => {
+                    *paxos_status = PaxosStatus::ConsensusReached(value);
+                }
+                LogEntry::Vote { term, voted_for } => election.restore(term, voted_for),
+            }
+        }
+
+        // Recovered processes get a fresh lease, detector and check schedule
+        processes
+            .values()
+            .for_each(|instance| self.start_tracking(instance));
+        *self.storage.lock().unwrap() = Some(storage);
+
+        self.log(&format!(
+            "Recovered {} processes at revision {} from {}",
+            processes.len(),
+            change_log.revision(),
+            data_dir.display()
+        ));
+        Ok(())
+    }
+
+    /// Appends `entry` to the log, if the registry has one.
+    pub(super) fn persist(&self, entry: &LogEntry) -> std::io::Result<()> {
+        match &mut *self.storage.lock().unwrap() {
+            Some(storage) => storage.append(entry),
+            None => Ok(()),
+        }
+    }
+
+    pub(super) fn persist_consensus(&self, value: PaxosAcceptedValue) {
+        let revision = self.revision();
+        if let Err(e) = self.persist(&LogEntry::Consensus { revision, value }) {
+            self.log(&format!("Couldn't persist consensus: {}", e));
+        }
+    }
+
+    /// Syncs the log as the fsync policy requires, and replaces it with a
+    /// snapshot once it grew long enough or `force` is set.
+    pub(super) fn maintain_storage(&self, force: bool) {
+        if self.persistence.is_none() {
+            return;
+        }
+
+        // Whatever is logged from here on may be missing from the snapshot,
+        // so it's kept in the log
+        let logged_before = {
+            let Some(storage) = &mut *self.storage.lock().unwrap() else {
+                return;
+            };
+            if let Err(e) = storage.sync_if_due() {
+                self.log(&format!("Couldn't sync log: {}", e));
+            }
+            if !force && !storage.needs_snapshot() {
+                return;
+            }
+            match storage.log_len() {
+                Ok(logged_before) => logged_before,
+                Err(e) => {
+                    self.log(&format!("Couldn't write snapshot: {}", e));
+                    return;
+                }
+            }
+        };
+
+        // Taken without holding the log, which changes are appended to
+        let snapshot = self.snapshot();
+        if let Some(storage) = &mut *self.storage.lock().unwrap() {
+            match storage.write_snapshot(&snapshot, logged_before) {
+                Ok(()) => self.log(&format!("Snapshot at revision {}", snapshot.revision)),
+                Err(e) => self.log(&format!("Couldn't write snapshot: {}", e)),
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        let (term, voted_for) = {
+            let election = self.election.lock().unwrap();
+            (election.term(), election.voted_for())
+        };
+        let processes = self.processes.lock().unwrap();
+        let last_registered_id = self.last_registered_id.lock().unwrap();
+        let paxos_status = self.paxos_status.lock().unwrap();
+        let change_log = self.changes.lock().unwrap();
+
+        Snapshot {
+            revision: change_log.revision(),
+            revision_term: change_log.term(),
+            last_registered_id: *last_registered_id,
+            processes: processes.clone(),
+            consensus: match &*paxos_status {
+                PaxosStatus::ConsensusReached(value) => Some(*value),
+                _ => None,
+            },
+            term,
+            voted_for,
+        }
+    }
+}
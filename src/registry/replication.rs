@@ -0,0 +1,774 @@
+use std::{
+    collections::HashMap,
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{watch::ChangeLog, Registry};
+use crate::{
+    algorithms::{BroadcastMode, BroadcastOutcome, Logger},
+    cluster::{ClusterConfig, Election},
+    events::{ClusterEvent, Envelope, Event, ProcessEvent, RegistryEvent},
+    service::{MembershipDelta, ServiceInstance},
+    storage::LogEntry,
+    Broadcast, P2PSend,
+};
+
+/// How often followers check whether the leader went quiet.
+pub const ELECTION_TICK: Duration = Duration::from_millis(50);
+
+impl Registry {
+    /// Whether this node takes writes and probes, expires and checks
+    /// processes. Always true outside a cluster.
+    pub(super) fn is_leader(&self) -> bool {
+        self.cluster.is_none() || self.election.lock().unwrap().is_leader()
+    }
+
+    pub(super) fn node_id(&self) -> Option<u32> {
+        self.cluster.as_ref().map(|cluster| cluster.node_id)
+    }
+
+    /// Address of the leader, as far as this node knows.
+    fn leader_address(&self) -> Option<String> {
+        let cluster = self.cluster.as_ref()?;
+        let leader = self.election.lock().unwrap().leader()?;
+        cluster.peers.get(&leader).cloned()
+    }
+
+    /// Handles a write sent to a follower: processes are told where the
+    /// leader is.
+    pub(super) fn redirect_to_leader(&self, envelope: Envelope) -> Envelope {
+        Envelope::reply_to(
+            envelope.correlation_id,
+            self.node_id(),
+            RegistryEvent::NotLeader {
+                revision: self.revision(),
+                leader: self.leader_address(),
+            },
+        )
+    }
+
+    /// Whether `envelope` is a deregistration sent to a follower, which is
+    /// forwarded to the leader as the process doesn't wait for an answer.
+    pub(super) fn must_forward(&self, envelope: &Envelope) -> bool {
+        matches!(
+            envelope.event,
+            Event::ProcessEvent(ProcessEvent::Deregister { .. })
+        ) && !self.is_leader()
+    }
+
+    /// Leader to forward `envelope` to, if the cluster has one.
+    pub(super) fn forward_target(&self, envelope: &Envelope) -> Option<String> {
+        let leader = self.leader_address();
+        if leader.is_none() {
+            self.log(&format!("No leader to forward {:?} to", envelope.event));
+        }
+        leader
+    }
+
+    pub(super) fn forward_to_leader(&self, envelope: &Envelope) {
+        if let Some(leader) = self.forward_target(envelope) {
+            if let Err(e) = Registry::send(
+                &leader,
+                &envelope.as_bytes_vec(self.codec),
+                self.max_frame_size,
+            ) {
+                self.log(&format!("Couldn't forward to leader {}: {}", leader, e));
+            }
+        }
+    }
+
+    /// Revision a majority of the cluster must hold before `response` is
+    /// sent: registrations are only acknowledged once they'd survive the
+    /// leader failing.
+    pub(super) fn needs_quorum(&self, response: &Envelope) -> Option<u64> {
+        self.cluster.as_ref()?;
+        match response.event {
+            Event::RegistryEvent(RegistryEvent::Registered { revision, .. }) => Some(revision),
+            _ => None,
+        }
+    }
+
+    /// Holds `response` back until a majority of the cluster holds
+    /// `revision`. Registrations that weren't replicated in time are
+    /// answered as if there was no leader, so the process tries again.
+    pub(super) fn acknowledge(&self, response: Envelope) -> Envelope {
+        match self.needs_quorum(&response) {
+            Some(revision) if !self.replicate_to_quorum(revision) => {
+                self.not_replicated(response, revision)
+            }
+            _ => response,
+        }
+    }
+
+    pub(super) fn not_replicated(&self, response: Envelope, revision: u64) -> Envelope {
+        self.log(&format!(
+            "Revision {} not replicated to a majority in time",
+            revision
+        ));
+        Envelope::reply_to(
+            response.correlation_id,
+            self.node_id(),
+            RegistryEvent::NotLeader {
+                revision: self.revision(),
+                leader: None,
+            },
+        )
+    }
+
+    /// Replicates to followers until a majority of the cluster holds
+    /// `revision`, for at most an election timeout. Returns whether it
+    /// does.
+    fn replicate_to_quorum(&self, revision: u64) -> bool {
+        let Some(cluster) = &self.cluster else {
+            return true;
+        };
+
+        let deadline = Instant::now() + cluster.election_timeout;
+        while !self.replicated_by_quorum(revision) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || !self.is_leader() {
+                return false;
+            }
+            self.replicate_with(BroadcastMode::Quorum(cluster.quorum() - 1), remaining);
+            if !self.replicated_by_quorum(revision) {
+                self.shutdown.wait_timeout(ELECTION_TICK);
+            }
+        }
+        true
+    }
+
+    /// Whether this leader and enough followers to make a majority hold the
+    /// table at `revision` or later.
+    pub(super) fn replicated_by_quorum(&self, revision: u64) -> bool {
+        let Some(cluster) = &self.cluster else {
+            return true;
+        };
+
+        let followers = self.followers.lock().unwrap();
+        let holding = followers
+            .values()
+            .filter(|acknowledged| {
+                acknowledged.is_some_and(|acknowledged| acknowledged >= revision)
+            })
+            .count();
+        self.is_leader() && holding + 1 >= cluster.quorum()
+    }
+
+    /// Runs an election once the leader went quiet.
+    pub(super) fn run_election(&self) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+
+        if let Some((term, request)) = self.vote_request() {
+            let outcome = Registry::request_with(
+                &cluster.peers,
+                &request,
+                self.codec,
+                self.max_frame_size,
+                BroadcastMode::All,
+                cluster.election_timeout / 2,
+            );
+            self.count_votes(term, outcome);
+        }
+    }
+
+    /// Sends every follower the changes it's missing, or a heartbeat.
+    pub(super) fn replicate(&self) {
+        if let Some(cluster) = &self.cluster {
+            self.replicate_with(BroadcastMode::All, cluster.heartbeat_interval);
+        }
+    }
+
+    fn replicate_with(&self, mode: BroadcastMode, deadline: Duration) {
+        thread::scope(|s| {
+            for (peers, request) in self.replication_requests() {
+                s.spawn(move || {
+                    let outcome = Registry::request_with(
+                        &peers,
+                        &request,
+                        self.codec,
+                        self.max_frame_size,
+                        mode,
+                        deadline,
+                    );
+                    outcome
+                        .into_iter()
+                        .for_each(|(id, response)| self.replicated(id, response));
+                });
+            }
+        });
+    }
+
+    /// Moves to a new term as a candidate once the election timeout
+    /// passed, returning the term and the vote request to send every peer.
+    pub(super) fn vote_request(&self) -> Option<(u64, Envelope)> {
+        let cluster = self.cluster.as_ref()?;
+        let election = &mut *self.election.lock().unwrap();
+        if !election.election_is_due() {
+            return None;
+        }
+
+        let term = election.start_election(cluster.node_id);
+        if let Err(e) = self.persist_vote(election) {
+            self.log(&format!("Couldn't persist vote: {}", e));
+        }
+        self.log(&format!("Starting election for term {}", term));
+
+        let change_log = self.changes.lock().unwrap();
+        let request = Envelope::new(
+            self.node_id(),
+            ClusterEvent::RequestVote {
+                term,
+                candidate_id: cluster.node_id,
+                revision: change_log.revision(),
+                last_term: change_log.term(),
+            },
+        );
+        Some((term, request))
+    }
+
+    /// Takes the lead of `term` if a majority voted for this node.
+    pub(super) fn count_votes(&self, term: u64, outcome: BroadcastOutcome<Envelope>) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+
+        let mut votes = 1;
+        for response in outcome.into_values().flatten() {
+            match response.event {
+                Event::ClusterEvent(ClusterEvent::Vote {
+                    term: voter_term,
+                    granted,
+                }) => {
+                    if self.observe_term(voter_term) {
+                        return;
+                    }
+                    if granted {
+                        votes += 1;
+                    }
+                }
+                _ => self.log("Unexpected response to a vote request"),
+            }
+        }
+
+        if votes >= cluster.quorum() && self.election.lock().unwrap().win(cluster.node_id, term) {
+            self.log(&format!(
+                "Elected leader for term {} with {} votes",
+                term, votes
+            ));
+            self.take_over(cluster, term);
+        } else {
+            self.log(&format!(
+                "Not elected for term {}, {} votes out of {} needed",
+                term,
+                votes,
+                cluster.quorum()
+            ));
+        }
+    }
+
+    /// Starts doing the leader's work. Leases, failure detectors and health
+    /// checks start afresh for every process, and followers get a snapshot
+    /// first, whatever they had before this term.
+    fn take_over(&self, cluster: &ClusterConfig, term: u64) {
+        let processes = self.processes.lock().unwrap();
+        self.changes.lock().unwrap().lead(term);
+        self.leases.lock().unwrap().clear();
+        self.health_checks.lock().unwrap().clear();
+        self.detectors.lock().unwrap().clear();
+        self.suspected_since.lock().unwrap().clear();
+        processes
+            .values()
+            .for_each(|instance| self.start_tracking(instance));
+
+        *self.followers.lock().unwrap() = cluster.peers.keys().map(|id| (*id, None)).collect();
+        // Processes registered with the previous leader get the table again
+        *self.last_pushed_revision.lock().unwrap() = 0;
+        self.pending_replication.notify();
+    }
+
+    /// What every follower is missing, grouped by the revision it's at so
+    /// each group gets a single request.
+    pub(super) fn replication_requests(&self) -> Vec<(HashMap<u32, String>, Envelope)> {
+        let Some(cluster) = &self.cluster else {
+            return vec![];
+        };
+        let term = {
+            let election = self.election.lock().unwrap();
+            if !election.is_leader() {
+                return vec![];
+            }
+            election.term()
+        };
+
+        let followers = self.followers.lock().unwrap().clone();
+        let processes = self.processes.lock().unwrap();
+        let last_registered_id = *self.last_registered_id.lock().unwrap();
+        let change_log = self.changes.lock().unwrap();
+
+        let mut groups: HashMap<Option<u64>, HashMap<u32, String>> = HashMap::new();
+        for (id, addr) in &cluster.peers {
+            let revision = followers.get(id).copied().flatten();
+            groups
+                .entry(revision)
+                .or_default()
+                .insert(*id, addr.clone());
+        }
+
+        groups
+            .into_iter()
+            .map(|(revision, peers)| {
+                let deltas = revision.and_then(|since| {
+                    Some((
+                        since,
+                        change_log.term_at(since)?,
+                        change_log.since(since, None)?,
+                    ))
+                });
+                let cluster_event = match deltas {
+                    Some((since, since_term, deltas)) => ClusterEvent::AppendChanges {
+                        term,
+                        leader_id: cluster.node_id,
+                        since,
+                        since_term,
+                        last_registered_id,
+                        deltas,
+                    },
+                    None => ClusterEvent::InstallSnapshot {
+                        term,
+                        leader_id: cluster.node_id,
+                        revision: change_log.revision(),
+                        revision_term: change_log.term(),
+                        last_registered_id,
+                        processes: processes.clone(),
+                    },
+                };
+                (peers, Envelope::new(self.node_id(), cluster_event))
+            })
+            .collect()
+    }
+
+    /// Moves a follower forward once it acknowledged a request. Followers
+    /// that refused one get a snapshot next.
+    pub(super) fn replicated(&self, peer_id: u32, response: std::io::Result<Envelope>) {
+        match response {
+            Ok(Envelope {
+                event:
+                    Event::ClusterEvent(ClusterEvent::Replicated {
+                        term,
+                        revision,
+                        success,
+                    }),
+                ..
+            }) => {
+                if self.observe_term(term) {
+                    return;
+                }
+                let followers = &mut *self.followers.lock().unwrap();
+                if !success && followers.get(&peer_id) != Some(&None) {
+                    self.log(&format!(
+                        "Node {} is at revision {}, sending it a snapshot",
+                        peer_id, revision
+                    ));
+                }
+                // Overlapping requests may be answered out of order
+                let acknowledged = match followers.get(&peer_id) {
+                    Some(&Some(acknowledged)) if success => acknowledged.max(revision),
+                    _ => revision,
+                };
+                followers.insert(peer_id, success.then_some(acknowledged));
+            }
+            Ok(_) => self.log(&format!("Unexpected response from node {}", peer_id)),
+            // The node catches up once it's back
+            Err(_) => {}
+        }
+    }
+
+    /// Answers a request from another node of the cluster.
+    pub(super) fn handle_cluster_event(&self, cluster_event: ClusterEvent) -> Option<ClusterEvent> {
+        if self.cluster.is_none() {
+            self.log("Cluster event outside of a cluster");
+            return None;
+        }
+
+        match cluster_event {
+            ClusterEvent::RequestVote {
+                term,
+                candidate_id,
+                revision,
+                last_term,
+            } => Some(self.vote(term, candidate_id, (last_term, revision))),
+            ClusterEvent::AppendChanges {
+                term,
+                leader_id,
+                since,
+                since_term,
+                last_registered_id,
+                deltas,
+            } => Some(self.append_changes(
+                term,
+                leader_id,
+                (since_term, since),
+                last_registered_id,
+                deltas,
+            )),
+            ClusterEvent::InstallSnapshot {
+                term,
+                leader_id,
+                revision,
+                revision_term,
+                last_registered_id,
+                processes,
+            } => Some(self.install_snapshot(
+                term,
+                leader_id,
+                (revision_term, revision),
+                last_registered_id,
+                processes,
+            )),
+            ClusterEvent::Vote { .. } | ClusterEvent::Replicated { .. } => {
+                self.log("Cluster response outside of a request");
+                None
+            }
+        }
+    }
+
+    /// Grants the vote only to a candidate whose last change, as `(term,
+    /// revision)`, is at least as recent as this node's.
+    fn vote(&self, term: u64, candidate_id: u32, last_change: (u64, u64)) -> ClusterEvent {
+        self.observe_term(term);
+        let election = &mut *self.election.lock().unwrap();
+        let own_last_change = {
+            let change_log = self.changes.lock().unwrap();
+            (change_log.term(), change_log.revision())
+        };
+        let mut granted = election.vote(term, candidate_id, last_change >= own_last_change);
+        if granted {
+            // A vote that could be forgotten on restart mustn't be handed out
+            if let Err(e) = self.persist_vote(election) {
+                self.log(&format!("Couldn't persist vote: {}", e));
+                granted = false;
+            } else {
+                self.log(&format!("Voted for node {} in term {}", candidate_id, term));
+            }
+        }
+
+        ClusterEvent::Vote {
+            term: election.term(),
+            granted,
+        }
+    }
+
+    /// Applies the deltas following `since`, as `(term, revision)`, unless
+    /// this node doesn't have the change at `since` from the same term, or
+    /// holds later ones from another leader: it then missed changes or has
+    /// some a previous leader didn't replicate, and needs a snapshot.
+    /// Deltas the node already holds, as requests overlap, are skipped.
+    fn append_changes(
+        &self,
+        term: u64,
+        leader_id: u32,
+        (since_term, since): (u64, u64),
+        last_registered_id: u32,
+        mut deltas: Vec<MembershipDelta>,
+    ) -> ClusterEvent {
+        if let Err(term) = self.heard_from_leader(term, leader_id) {
+            return self.refuse_replication(term);
+        }
+
+        let processes = &mut *self.processes.lock().unwrap();
+        let matches = {
+            let change_log = self.changes.lock().unwrap();
+            let held = deltas
+                .iter()
+                .take_while(|delta| delta.revision <= change_log.revision())
+                .count();
+            // Changes match up to the last one both have if they match there
+            let (last_held_term, last_held) = match held.checked_sub(1) {
+                Some(last) => (deltas[last].term, deltas[last].revision),
+                None => (since_term, since),
+            };
+            let holds_more = held == deltas.len() && change_log.revision() > last_held;
+            deltas.drain(..held);
+            change_log.term_at(last_held) == Some(last_held_term)
+                && !(holds_more && change_log.term() != term)
+        };
+        // Refusing reports the revision, which locks the log again
+        if !matches {
+            return self.refuse_replication(term);
+        }
+        if let Err(e) = self.append_deltas(processes, deltas) {
+            self.log(&format!("Couldn't persist replicated changes: {}", e));
+            return self.refuse_replication(term);
+        }
+        let known_last_registered_id = &mut *self.last_registered_id.lock().unwrap();
+        *known_last_registered_id = (*known_last_registered_id).max(last_registered_id);
+
+        ClusterEvent::Replicated {
+            term,
+            revision: self.revision(),
+            success: true,
+        }
+    }
+
+    fn install_snapshot(
+        &self,
+        term: u64,
+        leader_id: u32,
+        (revision_term, revision): (u64, u64),
+        last_registered_id: u32,
+        processes: HashMap<u32, ServiceInstance>,
+    ) -> ClusterEvent {
+        if let Err(term) = self.heard_from_leader(term, leader_id) {
+            return self.refuse_replication(term);
+        }
+
+        {
+            let table = &mut *self.processes.lock().unwrap();
+            *table = processes;
+            *self.last_registered_id.lock().unwrap() = last_registered_id;
+            *self.changes.lock().unwrap() = ChangeLog::starting_at(revision, revision_term);
+        }
+        self.log(&format!(
+            "Installed snapshot at revision {} from node {}",
+            revision, leader_id
+        ));
+        // The log restarts from the new table, so do watchers
+        self.resync_watchers();
+        self.maintain_storage(true);
+
+        ClusterEvent::Replicated {
+            term,
+            revision,
+            success: true,
+        }
+    }
+
+    fn refuse_replication(&self, term: u64) -> ClusterEvent {
+        ClusterEvent::Replicated {
+            term,
+            revision: self.revision(),
+            success: false,
+        }
+    }
+
+    /// Follows the leader of `term`, unless this node is already in a newer
+    /// term, which is returned.
+    fn heard_from_leader(&self, term: u64, leader_id: u32) -> Result<(), u64> {
+        self.observe_term(term);
+        let election = &mut *self.election.lock().unwrap();
+        if term < election.term() {
+            return Err(election.term());
+        }
+
+        if election.leader() != Some(leader_id) {
+            self.log(&format!("Following node {} in term {}", leader_id, term));
+        }
+        election.follow(leader_id);
+        Ok(())
+    }
+
+    /// Steps down if another node is in a newer term, returns whether it
+    /// was.
+    fn observe_term(&self, term: u64) -> bool {
+        let election = &mut *self.election.lock().unwrap();
+        let was_leader = election.is_leader();
+        if !election.observe_term(term) {
+            return false;
+        }
+
+        if let Err(e) = self.persist_vote(election) {
+            self.log(&format!("Couldn't persist vote: {}", e));
+        }
+        if was_leader {
+            self.log(&format!("Stepping down, node in term {}", term));
+        }
+        true
+    }
+
+    fn persist_vote(&self, election: &Election) -> std::io::Result<()> {
+        self.persist(&LogEntry::Vote {
+            term: election.term(),
+            voted_for: election.voted_for(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codec::CodecKind, failure_detector::FailureDetectorConfig, framing::DEFAULT_MAX_FRAME_SIZE,
+        health::HealthCheckPolicy, registry::watch::MAX_RETAINED_DELTAS, service::MembershipChange,
+    };
+
+    /// Node `node_id` of a three node cluster whose peers are never reached.
+    fn cluster_node(node_id: u32) -> Registry {
+        let peers = [1, 2, 3]
+            .into_iter()
+            .filter(|id| *id != node_id)
+            .map(|id| (id, format!("127.0.0.1:{}", id)))
+            .collect();
+        Registry::new(
+            CodecKind::default(),
+            DEFAULT_MAX_FRAME_SIZE,
+            FailureDetectorConfig::default(),
+            None,
+            Some(ClusterConfig::new(node_id, peers)),
+            HealthCheckPolicy::default(),
+        )
+    }
+
+    fn leader(node_id: u32) -> Registry {
+        let registry = cluster_node(node_id);
+        let term = registry.election.lock().unwrap().start_election(node_id);
+        assert!(registry.election.lock().unwrap().win(node_id, term));
+        registry.take_over(registry.cluster.as_ref().unwrap(), term);
+        registry
+    }
+
+    fn delta(revision: u64, term: u64, id: u32) -> MembershipDelta {
+        MembershipDelta {
+            revision,
+            term,
+            change: MembershipChange::Added(ServiceInstance {
+                instance_id: id,
+                ..ServiceInstance::new("test", "1")
+            }),
+        }
+    }
+
+    fn append(
+        registry: &Registry,
+        term: u64,
+        since: (u64, u64),
+        deltas: Vec<MembershipDelta>,
+    ) -> Option<ClusterEvent> {
+        registry.handle_cluster_event(ClusterEvent::AppendChanges {
+            term,
+            leader_id: 1,
+            since: since.1,
+            since_term: since.0,
+            last_registered_id: deltas
+                .iter()
+                .map(|delta| delta.revision as u32)
+                .max()
+                .unwrap_or(0),
+            deltas,
+        })
+    }
+
+    fn replicated(term: u64, revision: u64, success: bool) -> std::io::Result<Envelope> {
+        Ok(Envelope::new(
+            None,
+            ClusterEvent::Replicated {
+                term,
+                revision,
+                success,
+            },
+        ))
+    }
+
+    #[test]
+    fn applies_changes_following_its_own() {
+        let follower = cluster_node(2);
+        let response = append(&follower, 1, (0, 0), vec![delta(1, 1, 1), delta(2, 1, 2)]);
+
+        assert!(matches!(
+            response,
+            Some(ClusterEvent::Replicated {
+                term: 1,
+                revision: 2,
+                success: true
+            })
+        ));
+        assert_eq!(follower.processes.lock().unwrap().len(), 2);
+        assert_eq!(*follower.last_registered_id.lock().unwrap(), 2);
+
+        // A request overlapping what it already holds only adds the rest
+        let response = append(&follower, 1, (0, 0), vec![delta(1, 1, 1), delta(3, 1, 3)]);
+        assert!(matches!(
+            response,
+            Some(ClusterEvent::Replicated {
+                revision: 3,
+                success: true,
+                ..
+            })
+        ));
+        assert_eq!(follower.processes.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn refuses_changes_after_holding_later_ones_from_another_term() {
+        let follower = cluster_node(2);
+        append(&follower, 1, (0, 0), vec![delta(1, 1, 1), delta(2, 1, 2)]);
+
+        // The leader of term 2 only has the first change of term 1
+        let response = append(&follower, 2, (1, 1), vec![]);
+        assert!(matches!(
+            response,
+            Some(ClusterEvent::Replicated {
+                term: 2,
+                revision: 2,
+                success: false
+            })
+        ));
+
+        // Nor does it take changes that don't follow its own
+        let response = append(&follower, 2, (2, 2), vec![delta(3, 2, 3)]);
+        assert!(matches!(
+            response,
+            Some(ClusterEvent::Replicated { success: false, .. })
+        ));
+        assert_eq!(follower.revision(), 2);
+    }
+
+    #[test]
+    fn sends_a_snapshot_to_a_follower_behind_the_retained_changes() {
+        let leader = leader(1);
+        let term = leader.election.lock().unwrap().term();
+        for id in 1..=MAX_RETAINED_DELTAS as u32 + 1 {
+            leader.record_changes([delta(0, 0, id).change]).unwrap();
+        }
+        leader.replicated(2, replicated(term, 0, true));
+        leader.replicated(3, replicated(term, 1, true));
+
+        let requests = leader.replication_requests();
+        let request_to = |peer| {
+            requests
+                .iter()
+                .find(|(peers, _)| peers.contains_key(&peer))
+                .map(|(_, request)| request.event.clone())
+                .unwrap()
+        };
+        assert!(matches!(
+            request_to(2),
+            Event::ClusterEvent(ClusterEvent::InstallSnapshot { .. })
+        ));
+        assert!(matches!(
+            request_to(3),
+            Event::ClusterEvent(ClusterEvent::AppendChanges { since: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn acknowledges_a_revision_once_a_majority_holds_it() {
+        let leader = leader(1);
+        let term = leader.election.lock().unwrap().term();
+        leader
+            .record_changes([delta(0, 0, 1).change, delta(0, 0, 2).change])
+            .unwrap();
+
+        assert!(!leader.replicated_by_quorum(2));
+        leader.replicated(2, replicated(term, 1, true));
+        assert!(!leader.replicated_by_quorum(2));
+        leader.replicated(3, replicated(term, 2, true));
+        assert!(leader.replicated_by_quorum(2));
+
+        // An older answer arriving late doesn't move the follower back
+        leader.replicated(3, replicated(term, 1, true));
+        assert!(leader.replicated_by_quorum(2));
+    }
+}
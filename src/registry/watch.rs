@@ -0,0 +1,378 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+
+use super::Registry;
+use crate::{
+    algorithms::Logger,
+    events::{Envelope, RegistryEvent},
+    service::{MembershipChange, MembershipDelta, ServiceInstance},
+    storage::LogEntry,
+    P2PSend,
+};
+
+/// Deltas kept for watchers resuming from an older revision. Watchers
+/// further behind get a snapshot instead.
+pub const MAX_RETAINED_DELTAS: usize = 1024;
+
+/// Every change made to the membership table, numbered by the revision it
+/// produced and tagged with the term it was made in.
+#[derive(Debug, Default)]
+pub struct ChangeLog {
+    revision: u64,
+    /// Term of the change that produced `revision`.
+    term: u64,
+    /// Term new changes are made in.
+    leader_term: u64,
+    deltas: VecDeque<MembershipDelta>,
+    /// Term of the change just before the oldest retained delta.
+    base_term: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Watcher {
+    service_name: Option<String>,
+    /// Last revision pushed to the watcher, `None` until it got a snapshot.
+    revision: Option<u64>,
+}
+
+/// Wakes the thread pushing to watchers up when the table changed.
+#[derive(Debug, Default)]
+pub struct PendingPush {
+    pending: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl ChangeLog {
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// A log resuming from a table at `revision`, produced in `term`.
+    pub fn starting_at(revision: u64, term: u64) -> Self {
+        ChangeLog {
+            revision,
+            term,
+            leader_term: 0,
+            deltas: VecDeque::new(),
+            base_term: term,
+        }
+    }
+
+    /// Makes the following changes in `term`, once this node leads it.
+    pub fn lead(&mut self, term: u64) {
+        self.leader_term = term;
+    }
+
+    /// The delta `change` would make next, left for [`ChangeLog::append`]
+    /// to take in.
+    pub fn next_delta(&self, change: MembershipChange) -> MembershipDelta {
+        MembershipDelta {
+            revision: self.revision + 1,
+            term: self.leader_term,
+            change,
+        }
+    }
+
+    /// Appends a change made elsewhere, keeping its revision and term.
+    pub fn append(&mut self, delta: MembershipDelta) -> &MembershipDelta {
+        self.revision = delta.revision;
+        self.term = delta.term;
+        self.deltas.push_back(delta);
+        if self.deltas.len() > MAX_RETAINED_DELTAS {
+            if let Some(discarded) = self.deltas.pop_front() {
+                self.base_term = discarded.term;
+            }
+        }
+        self.deltas.back().unwrap()
+    }
+
+    /// Term of the change that produced `revision`, or `None` if it was
+    /// already discarded or is yet to come.
+    pub fn term_at(&self, revision: u64) -> Option<u64> {
+        let oldest_revision = self
+            .deltas
+            .front()
+            .map_or(self.revision + 1, |delta| delta.revision);
+        if revision == self.revision {
+            Some(self.term)
+        } else if revision + 1 == oldest_revision {
+            Some(self.base_term)
+        } else {
+            self.deltas
+                .iter()
+                .find(|delta| delta.revision == revision)
+                .map(|delta| delta.term)
+        }
+    }
+
+    /// Deltas concerning `service_name` after revision `since`, or `None` if
+    /// some of them were already discarded.
+    pub fn since(&self, since: u64, service_name: Option<&str>) -> Option<Vec<MembershipDelta>> {
+        let oldest_revision = self
+            .deltas
+            .front()
+            .map_or(self.revision + 1, |delta| delta.revision);
+        if since > self.revision || since + 1 < oldest_revision {
+            return None;
+        }
+
+        Some(
+            self.deltas
+                .iter()
+                .filter(|delta| delta.revision > since && delta.change.concerns(service_name))
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+impl PendingPush {
+    pub fn notify(&self) {
+        *self.pending.lock().unwrap() = true;
+        self.condvar.notify_all();
+    }
+
+    /// Waits at most `timeout` for a push to be requested, and clears the
+    /// request.
+    pub fn take(&self, timeout: Duration) -> bool {
+        let pending = self.pending.lock().unwrap();
+        let (mut pending, _) = self
+            .condvar
+            .wait_timeout_while(pending, timeout, |pending| !*pending)
+            .unwrap();
+        std::mem::take(&mut *pending)
+    }
+}
+
+impl Registry {
+    /// Current revision of the membership table.
+    pub(super) fn revision(&self) -> u64 {
+        self.changes.lock().unwrap().revision()
+    }
+
+    /// Logs each change once it's persisted, stopping at the first that
+    /// couldn't be: the caller mustn't apply that one, nor the rest. Must be
+    /// called with the processes table still locked, so revisions follow the
+    /// order changes were made in.
+    pub(super) fn record_changes(
+        &self,
+        changes: impl IntoIterator<Item = MembershipChange>,
+    ) -> std::io::Result<()> {
+        let change_log = &mut *self.changes.lock().unwrap();
+        let revision = change_log.revision();
+
+        let recorded = changes.into_iter().try_for_each(|change| {
+            let delta = change_log.next_delta(change);
+            self.persist(&LogEntry::Membership(Box::new(delta.clone())))?;
+            change_log.append(delta);
+            Ok(())
+        });
+        if change_log.revision() != revision {
+            self.pending_push.notify();
+            self.pending_replication.notify();
+        }
+        recorded
+    }
+
+    /// Applies the deltas a leader replicated to `processes` and logs them
+    /// as they are, each once it's persisted, as [`Registry::record_changes`].
+    pub(super) fn append_deltas(
+        &self,
+        processes: &mut HashMap<u32, ServiceInstance>,
+        deltas: Vec<MembershipDelta>,
+    ) -> std::io::Result<()> {
+        if deltas.is_empty() {
+            return Ok(());
+        }
+
+        let change_log = &mut *self.changes.lock().unwrap();
+        let appended = deltas.into_iter().try_for_each(|delta| {
+            self.persist(&LogEntry::Membership(Box::new(delta.clone())))?;
+            delta.change.apply(processes);
+            change_log.append(delta);
+            Ok(())
+        });
+        self.pending_push.notify();
+        appended
+    }
+
+    pub(super) fn add_watcher(
+        &self,
+        addr: String,
+        service_name: Option<String>,
+        since: Option<u64>,
+    ) {
+        self.log(&format!(
+            "{} watches {} since revision {:?}",
+            addr,
+            service_name.as_deref().unwrap_or("every service"),
+            since
+        ));
+
+        self.watchers.lock().unwrap().insert(
+            addr,
+            Watcher {
+                service_name,
+                revision: since,
+            },
+        );
+        self.pending_push.notify();
+    }
+
+    /// Sends every watcher a snapshot next, once the table was replaced.
+    pub(super) fn resync_watchers(&self) {
+        let watchers = &mut *self.watchers.lock().unwrap();
+        watchers
+            .values_mut()
+            .for_each(|watcher| watcher.revision = None);
+        self.pending_push.notify();
+    }
+
+    pub(super) fn remove_watchers(&self, addrs: impl IntoIterator<Item = String>) {
+        let watchers = &mut *self.watchers.lock().unwrap();
+        addrs.into_iter().for_each(|addr| {
+            watchers.remove(&addr);
+        });
+    }
+
+    /// What every watcher is missing, as `(address, revision, event)`.
+    pub(super) fn pending_watcher_updates(&self) -> Vec<(String, u64, Vec<u8>)> {
+        let watchers = self.watchers.lock().unwrap().clone();
+        let processes = self.processes.lock().unwrap();
+        let change_log = self.changes.lock().unwrap();
+        let revision = change_log.revision();
+
+        watchers
+            .into_iter()
+            .filter_map(|(addr, watcher)| {
+                let service_name = watcher.service_name.as_deref();
+                let deltas = watcher
+                    .revision
+                    .and_then(|since| Some((since, change_log.since(since, service_name)?)));
+
+                let registry_event = match deltas {
+                    Some((_, deltas)) if deltas.is_empty() => return None,
+                    Some((since, deltas)) => RegistryEvent::MembershipDeltas {
+                        since,
+                        revision,
+                        deltas,
+                    },
+                    None => RegistryEvent::MembershipSnapshot {
+                        revision,
+                        instances: watched_instances(&processes, service_name),
+                    },
+                };
+
+                let registry_event = Envelope::new(None, registry_event).as_bytes_vec(self.codec);
+                Some((addr, revision, registry_event))
+            })
+            .collect()
+    }
+
+    /// Moves a watcher forward once `revision` was pushed to it. Watchers
+    /// that couldn't be reached are dropped, they resync by watching again.
+    pub(super) fn watcher_pushed(&self, addr: &str, revision: u64, result: std::io::Result<usize>) {
+        let watchers = &mut *self.watchers.lock().unwrap();
+        match result {
+            Ok(_) => {
+                if let Some(watcher) = watchers.get_mut(addr) {
+                    watcher.revision = Some(revision);
+                }
+            }
+            Err(e) => {
+                self.log(&format!("Dropping watcher {}: {}", addr, e));
+                watchers.remove(addr);
+            }
+        }
+    }
+
+    pub(super) fn push_to_watchers(&self) {
+        for (addr, revision, registry_event) in self.pending_watcher_updates() {
+            let result = Registry::send(&addr, &registry_event, self.max_frame_size);
+            self.watcher_pushed(&addr, revision, result);
+        }
+    }
+}
+
+fn watched_instances(
+    processes: &HashMap<u32, ServiceInstance>,
+    service_name: Option<&str>,
+) -> HashMap<u32, ServiceInstance> {
+    processes
+        .iter()
+        .filter(|(_, instance)| instance.belongs_to(service_name))
+        .map(|(id, instance)| (*id, instance.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn added(id: u32, service_name: &str) -> MembershipChange {
+        MembershipChange::Added(ServiceInstance {
+            instance_id: id,
+            ..ServiceInstance::new(service_name, "1")
+        })
+    }
+
+    #[test]
+    fn knows_the_term_of_every_retained_change() {
+        let mut change_log = ChangeLog::starting_at(5, 2);
+        change_log.lead(3);
+        change_log.append(change_log.next_delta(added(1, "a")));
+        change_log.append(change_log.next_delta(added(2, "b")));
+
+        assert_eq!(change_log.revision(), 7);
+        assert_eq!(change_log.term_at(7), Some(3));
+        assert_eq!(change_log.term_at(6), Some(3));
+        assert_eq!(change_log.term_at(5), Some(2));
+        assert_eq!(change_log.term_at(4), None);
+        assert_eq!(change_log.term_at(8), None);
+    }
+
+    #[test]
+    fn returns_the_deltas_after_a_revision() {
+        let mut change_log = ChangeLog::starting_at(5, 2);
+        change_log.append(change_log.next_delta(added(1, "a")));
+        change_log.append(change_log.next_delta(added(2, "b")));
+
+        let revisions = |deltas: Option<Vec<MembershipDelta>>| {
+            deltas.map(|deltas| {
+                deltas
+                    .iter()
+                    .map(|delta| delta.revision)
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(revisions(change_log.since(5, None)), Some(vec![6, 7]));
+        assert_eq!(revisions(change_log.since(5, Some("b"))), Some(vec![7]));
+        assert_eq!(revisions(change_log.since(7, None)), Some(vec![]));
+        assert_eq!(revisions(change_log.since(8, None)), None);
+        assert_eq!(revisions(change_log.since(4, None)), None);
+    }
+
+    #[test]
+    fn falls_back_to_a_snapshot_once_changes_are_discarded() {
+        let mut change_log = ChangeLog::default();
+        for id in 1..=MAX_RETAINED_DELTAS as u32 + 1 {
+            change_log.append(change_log.next_delta(added(id, "a")));
+        }
+
+        assert_eq!(change_log.since(0, None), None);
+        assert_eq!(change_log.term_at(0), None);
+        // The change just before the oldest retained one is still known
+        assert_eq!(
+            change_log.since(1, None).map(|deltas| deltas.len()),
+            Some(MAX_RETAINED_DELTAS)
+        );
+        assert_eq!(change_log.term_at(1), Some(0));
+    }
+}
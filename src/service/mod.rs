@@ -0,0 +1,159 @@
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    failure_detector::Suspicion,
+    health::{HealthCheck, HealthStatus},
+};
+
+/// One registered instance of a named service.
+///
+/// Processes describe themselves with the name, version, tags, metadata,
+/// lease and health check when connecting; the registry fills in the instance
+/// id, address, port, registration time, health and suspicion.
+///
+/// A process connecting again sends its `instance_uuid`, so a registry that
+/// still knows it hands the same id back.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServiceInstance {
+    pub service_name: String,
+    pub instance_id: u32,
+    /// Picked by the process once for its whole lifetime.
+    #[serde(default)]
+    pub instance_uuid: Option<String>,
+    pub address: String,
+    pub port: u32,
+    pub version: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    pub registered_at: DateTime<Utc>,
+    /// Instances registered with a lease renew it before it lapses, instead
+    /// of being probed by the registry.
+    #[serde(default)]
+    pub lease_ttl: Option<Duration>,
+    /// Run by the registry against the instance, which is critical until
+    /// it first passes.
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    #[serde(default)]
+    pub health: HealthStatus,
+    /// `Suspect` while the instance misses the registry's heartbeats, until
+    /// it answers again or is removed.
+    #[serde(default)]
+    pub suspicion: Suspicion,
+}
+
+impl ServiceInstance {
+    pub fn new(service_name: impl Into<String>, version: impl Into<String>) -> Self {
+        ServiceInstance {
+            service_name: service_name.into(),
+            version: version.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the instance is part of `service_name`, `None` standing for
+    /// every service.
+    pub fn belongs_to(&self, service_name: Option<&str>) -> bool {
+        service_name.is_none_or(|service_name| self.service_name == service_name)
+    }
+
+    /// Address other nodes reach this instance on.
+    pub fn endpoint(&self) -> String {
+        format!("{}:{}", self.address, self.port)
+    }
+}
+
+/// Endpoints of `instances` by instance id, as expected by `Broadcast`.
+pub fn endpoints(instances: &HashMap<u32, ServiceInstance>) -> HashMap<u32, String> {
+    instances
+        .iter()
+        .map(|(id, instance)| (*id, instance.endpoint()))
+        .collect()
+}
+
+/// Question a client asks the registry to find instances of a dependency.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ServiceQuery {
+    ByName(String),
+    ByTag(String),
+    /// Instances whose metadata contains every one of these pairs.
+    ByMetadata(HashMap<String, String>),
+    ById(u32),
+}
+
+impl ServiceQuery {
+    pub fn matches(&self, instance: &ServiceInstance) -> bool {
+        match self {
+            ServiceQuery::ByName(service_name) => instance.service_name == *service_name,
+            ServiceQuery::ByTag(tag) => instance.tags.contains(tag),
+            ServiceQuery::ByMetadata(selector) => selector
+                .iter()
+                .all(|(key, value)| instance.metadata.get(key) == Some(value)),
+            ServiceQuery::ById(instance_id) => instance.instance_id == *instance_id,
+        }
+    }
+
+    /// Instances matching the query, ordered by instance id.
+    pub fn filter<'a>(
+        &self,
+        instances: impl IntoIterator<Item = &'a ServiceInstance>,
+    ) -> Vec<ServiceInstance> {
+        let mut matching: Vec<ServiceInstance> = instances
+            .into_iter()
+            .filter(|instance| self.matches(instance))
+            .cloned()
+            .collect();
+        matching.sort_by_key(|instance| instance.instance_id);
+        matching
+    }
+}
+
+/// A change to the registry's membership table.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum MembershipChange {
+    Added(ServiceInstance),
+    Removed(ServiceInstance),
+    Updated(ServiceInstance),
+}
+
+/// A membership change and the table revision it produced.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MembershipDelta {
+    pub revision: u64,
+    /// Term of the cluster leader that made the change, 0 outside a
+    /// cluster.
+    #[serde(default)]
+    pub term: u64,
+    pub change: MembershipChange,
+}
+
+impl MembershipChange {
+    pub fn instance(&self) -> &ServiceInstance {
+        match self {
+            MembershipChange::Added(instance)
+            | MembershipChange::Removed(instance)
+            | MembershipChange::Updated(instance) => instance,
+        }
+    }
+
+    /// Whether a watcher of `service_name` (or of everything) cares.
+    pub fn concerns(&self, service_name: Option<&str>) -> bool {
+        self.instance().belongs_to(service_name)
+    }
+
+    pub fn apply(&self, instances: &mut HashMap<u32, ServiceInstance>) {
+        match self {
+            MembershipChange::Added(instance) | MembershipChange::Updated(instance) => {
+                instances.insert(instance.instance_id, instance.clone());
+            }
+            MembershipChange::Removed(instance) => {
+                instances.remove(&instance.instance_id);
+            }
+        }
+    }
+}
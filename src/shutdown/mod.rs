@@ -0,0 +1,155 @@
+use std::{
+    io::{Error, ErrorKind},
+    net::TcpStream,
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// How often a blocked listener or an idle connection checks whether it
+/// should stop.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Stop flag shared by a node's listener, connection handlers and background
+/// loops.
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    requested: Mutex<bool>,
+    condvar: Condvar,
+    /// Why the node stopped on its own, if it failed.
+    error: Mutex<Option<Error>>,
+}
+
+impl Shutdown {
+    pub fn request(&self) {
+        *self.requested.lock().unwrap() = true;
+        self.condvar.notify_all();
+    }
+
+    /// Stops the node because of `error`, which it then returns.
+    pub fn fail(&self, error: Error) {
+        *self.error.lock().unwrap() = Some(error);
+        self.request();
+    }
+
+    /// What the node returns once stopped: the error it failed with, if
+    /// any.
+    pub fn outcome(&self) -> std::io::Result<()> {
+        self.error.lock().unwrap().take().map_or(Ok(()), Err)
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.requested.lock().unwrap()
+    }
+
+    /// Sleeps for `duration`, waking up early if shutdown is requested.
+    /// Returns whether it was.
+    pub fn wait_timeout(&self, duration: Duration) -> bool {
+        let requested = self.requested.lock().unwrap();
+        let (requested, _) = self
+            .condvar
+            .wait_timeout_while(requested, duration, |requested| !*requested)
+            .unwrap();
+        *requested
+    }
+
+    /// Waits until the next frame starts arriving on `stream`. Returns
+    /// `false` once shutdown is requested, so the connection is dropped
+    /// between frames rather than in the middle of one.
+    pub fn wait_readable(
+        &self,
+        stream: &TcpStream,
+        idle_timeout: Duration,
+    ) -> std::io::Result<bool> {
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let idle_since = Instant::now();
+
+        loop {
+            if self.is_requested() {
+                return Ok(false);
+            }
+            match stream.peek(&mut [0u8; 1]) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(_) => {
+                    stream.set_read_timeout(Some(idle_timeout))?;
+                    return Ok(true);
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if idle_since.elapsed() >= idle_timeout {
+                        return Err(ErrorKind::TimedOut.into());
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Async counterpart of [`Shutdown::wait_timeout`].
+    #[cfg(feature = "async")]
+    pub async fn wait_timeout_async(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+
+        while !self.is_requested() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            tokio::time::sleep(remaining.min(POLL_INTERVAL)).await;
+        }
+        true
+    }
+
+    /// Async counterpart of [`Shutdown::wait_readable`].
+    #[cfg(feature = "async")]
+    pub async fn wait_readable_async(
+        &self,
+        stream: &tokio::net::TcpStream,
+        idle_timeout: Duration,
+    ) -> std::io::Result<bool> {
+        let idle_since = Instant::now();
+
+        loop {
+            if self.is_requested() {
+                return Ok(false);
+            }
+            if let Ok(readable) = tokio::time::timeout(POLL_INTERVAL, stream.readable()).await {
+                return readable.map(|_| true);
+            }
+            if idle_since.elapsed() >= idle_timeout {
+                return Err(ErrorKind::TimedOut.into());
+            }
+        }
+    }
+}
+
+/// Returned by the non-blocking `start` of registries and processes to stop
+/// them from another thread.
+#[derive(Debug)]
+pub struct ShutdownHandle {
+    shutdown: Arc<Shutdown>,
+    thread: JoinHandle<std::io::Result<()>>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(shutdown: Arc<Shutdown>, thread: JoinHandle<std::io::Result<()>>) -> Self {
+        ShutdownHandle { shutdown, thread }
+    }
+
+    /// Stops the node and waits until its in-flight connections are drained.
+    pub fn shutdown(self) -> std::io::Result<()> {
+        self.shutdown.request();
+        self.join()
+    }
+
+    /// Waits for the node to stop on its own, e.g. a process that gave up on
+    /// its registry, which returns why.
+    pub fn join(self) -> std::io::Result<()> {
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(Error::other("node thread panicked")))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+}
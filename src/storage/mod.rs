@@ -0,0 +1,492 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    codec::{Codec, CodecKind},
+    events::PaxosAcceptedValue,
+    framing::{read_frame, write_frame, DEFAULT_MAX_FRAME_SIZE, FRAME_HEADER_SIZE},
+    service::{MembershipDelta, ServiceInstance},
+};
+
+const WAL_FILE: &str = "wal";
+const SNAPSHOT_FILE: &str = "snapshot";
+
+/// Log entries written before the registry snapshots again.
+pub const SNAPSHOT_EVERY: usize = 1000;
+
+/// When appends to the log are flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every append, nothing acknowledged is ever lost.
+    Always,
+    /// At most this long after an append.
+    Interval(Duration),
+    /// Whenever the OS sees fit.
+    Never,
+}
+
+/// Where and how durably the registry keeps its state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PersistenceConfig {
+    pub data_dir: PathBuf,
+    pub fsync: FsyncPolicy,
+}
+
+/// Registry state as of `revision`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    pub revision: u64,
+    /// Term of the change that produced `revision`.
+    #[serde(default)]
+    pub revision_term: u64,
+    pub last_registered_id: u32,
+    pub processes: HashMap<u32, ServiceInstance>,
+    pub consensus: Option<PaxosAcceptedValue>,
+    /// Election term of a cluster node, and whom it voted for in it.
+    #[serde(default)]
+    pub term: u64,
+    #[serde(default)]
+    pub voted_for: Option<u32>,
+}
+
+/// A mutation of the registry state, appended to the log before it's
+/// acknowledged.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LogEntry {
+    Membership(Box<MembershipDelta>),
+    /// Consensus reached while the table was at `revision`.
+    Consensus {
+        revision: u64,
+        value: PaxosAcceptedValue,
+    },
+    /// A cluster node moved to `term` or voted in it, which it must not
+    /// forget to never vote twice in a term.
+    Vote {
+        term: u64,
+        voted_for: Option<u32>,
+    },
+}
+
+/// Append-only log of registry mutations, compacted into a snapshot every
+/// `SNAPSHOT_EVERY` entries.
+#[derive(Debug)]
+pub struct Storage {
+    config: PersistenceConfig,
+    codec: CodecKind,
+    wal: File,
+    entries_since_snapshot: usize,
+    last_sync: Instant,
+    unsynced: bool,
+}
+
+impl FsyncPolicy {
+    /// Parses `always`, `never` or a number of milliseconds between syncs.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "always" => Some(FsyncPolicy::Always),
+            "never" => Some(FsyncPolicy::Never),
+            millis => millis
+                .parse()
+                .ok()
+                .map(|millis| FsyncPolicy::Interval(Duration::from_millis(millis))),
+        }
+    }
+}
+
+impl Storage {
+    /// Opens the data directory, creating it if needed, and returns the
+    /// latest snapshot along with the entries logged after it.
+    pub fn open(
+        config: PersistenceConfig,
+        codec: CodecKind,
+    ) -> std::io::Result<(Self, Snapshot, Vec<LogEntry>)> {
+        fs::create_dir_all(&config.data_dir)?;
+
+        let snapshot = match fs::read(config.data_dir.join(SNAPSHOT_FILE)) {
+            Ok(bytes) => decode(&bytes)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => return Err(e),
+        };
+
+        let wal_path = config.data_dir.join(WAL_FILE);
+        let wal = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&wal_path)?;
+        let (entries, valid_len) = read_log(&wal)?;
+        // Drop whatever a crash left half written
+        wal.set_len(valid_len)?;
+
+        let entries: Vec<LogEntry> = entries
+            .into_iter()
+            .filter(|entry| match entry {
+                LogEntry::Membership(delta) => delta.revision > snapshot.revision,
+                LogEntry::Consensus { revision, .. } => *revision >= snapshot.revision,
+                LogEntry::Vote { term, .. } => *term >= snapshot.term,
+            })
+            .collect();
+
+        let storage = Storage {
+            config,
+            codec,
+            wal,
+            entries_since_snapshot: entries.len(),
+            last_sync: Instant::now(),
+            unsynced: false,
+        };
+        Ok((storage, snapshot, entries))
+    }
+
+    pub fn append(&mut self, entry: &LogEntry) -> std::io::Result<()> {
+        write_frame(
+            &mut self.wal,
+            &encode(self.codec, entry)?,
+            DEFAULT_MAX_FRAME_SIZE,
+        )?;
+        self.entries_since_snapshot += 1;
+        self.unsynced = true;
+
+        match self.config.fsync {
+            FsyncPolicy::Always => self.sync(),
+            _ => self.sync_if_due(),
+        }
+    }
+
+    /// Syncs once the policy's interval has passed since the last sync.
+    pub fn sync_if_due(&mut self) -> std::io::Result<()> {
+        match self.config.fsync {
+            FsyncPolicy::Interval(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Flushes appends made since the last sync, unless the policy leaves
+    /// that to the OS.
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        if self.unsynced && self.config.fsync != FsyncPolicy::Never {
+            self.wal.sync_data()?;
+            self.unsynced = false;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    pub fn needs_snapshot(&self) -> bool {
+        self.entries_since_snapshot >= SNAPSHOT_EVERY
+    }
+
+    /// Length of the log so far, [`Storage::write_snapshot`] keeps what's
+    /// appended after it.
+    pub fn log_len(&self) -> std::io::Result<u64> {
+        Ok(self.wal.metadata()?.len())
+    }
+
+    /// Replaces the snapshot with `snapshot`, which holds everything logged
+    /// in the first `logged_before` bytes of the log, and keeps only the
+    /// rest of the log. The new snapshot is complete on disk before it
+    /// replaces the previous one.
+    pub fn write_snapshot(
+        &mut self,
+        snapshot: &Snapshot,
+        logged_before: u64,
+    ) -> std::io::Result<()> {
+        let path = self.config.data_dir.join(SNAPSHOT_FILE);
+        let tmp_path = path.with_extension("tmp");
+
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&encode(self.codec, snapshot)?)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        if let Ok(dir) = File::open(&self.config.data_dir) {
+            let _ = dir.sync_all();
+        }
+
+        // Entries up to the snapshot's revision are skipped on recovery, a
+        // crash before the log is cut loses nothing
+        let wal_path = self.config.data_dir.join(WAL_FILE);
+        let mut tail = vec![];
+        let mut wal = File::open(&wal_path)?;
+        wal.seek(SeekFrom::Start(logged_before))?;
+        wal.read_to_end(&mut tail)?;
+        if tail.is_empty() {
+            self.wal.set_len(0)?;
+            self.wal.sync_all()?;
+            self.entries_since_snapshot = 0;
+        } else {
+            let tmp_path = wal_path.with_extension("tmp");
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(&tail)?;
+            tmp.sync_all()?;
+            fs::rename(&tmp_path, &wal_path)?;
+            self.wal = OpenOptions::new().read(true).append(true).open(&wal_path)?;
+            self.entries_since_snapshot = read_log(&self.wal)?.0.len();
+        }
+        self.unsynced = false;
+        Ok(())
+    }
+}
+
+/// Every complete entry of the log, and the length they span.
+fn read_log(wal: &File) -> std::io::Result<(Vec<LogEntry>, u64)> {
+    let mut reader = BufReader::new(wal);
+    let mut entries = vec![];
+    let mut valid_len = 0;
+
+    loop {
+        match read_frame(&mut reader, DEFAULT_MAX_FRAME_SIZE) {
+            Ok(frame) => match decode(&frame) {
+                Ok(entry) => {
+                    entries.push(entry);
+                    valid_len += (FRAME_HEADER_SIZE + frame.len()) as u64;
+                }
+                Err(_) => break,
+            },
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) if e.kind() == ErrorKind::InvalidData => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok((entries, valid_len))
+}
+
+/// Stored values start with the id of the codec that wrote them, so the
+/// registry can be restarted with another codec.
+fn encode<T: Serialize>(codec: CodecKind, value: &T) -> std::io::Result<Vec<u8>> {
+    let mut bytes = vec![codec.id()];
+    bytes.extend(
+        codec
+            .encode(value)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+    );
+    Ok(bytes)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> std::io::Result<T> {
+    let (&codec_id, bytes) = bytes
+        .split_first()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "empty record"))?;
+    let codec = CodecKind::from_id(codec_id).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("unknown codec id {}", codec_id),
+        )
+    })?;
+
+    codec
+        .decode(bytes)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+    use crate::service::MembershipChange;
+
+    /// An empty data directory of its own for each test.
+    fn config(test: &str) -> PersistenceConfig {
+        let data_dir = env::temp_dir().join(format!("processes-{}-{}", test, process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+
+        PersistenceConfig {
+            data_dir,
+            fsync: FsyncPolicy::Always,
+        }
+    }
+
+    fn added(revision: u64, instance_id: u32) -> LogEntry {
+        LogEntry::Membership(Box::new(MembershipDelta {
+            revision,
+            term: 1,
+            change: MembershipChange::Added(ServiceInstance {
+                instance_id,
+                ..ServiceInstance::new("test", "1")
+            }),
+        }))
+    }
+
+    fn wal_len(config: &PersistenceConfig) -> u64 {
+        fs::metadata(config.data_dir.join(WAL_FILE)).unwrap().len()
+    }
+
+    fn append_raw(config: &PersistenceConfig, bytes: &[u8]) {
+        let mut wal = OpenOptions::new()
+            .append(true)
+            .open(config.data_dir.join(WAL_FILE))
+            .unwrap();
+        wal.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn entries_survive_a_restart() {
+        let config = config("round-trip");
+        let entries = vec![
+            added(1, 1),
+            LogEntry::Vote {
+                term: 2,
+                voted_for: Some(3),
+            },
+            added(2, 2),
+            LogEntry::Consensus {
+                revision: 2,
+                value: PaxosAcceptedValue {
+                    seq_number: 1,
+                    value: 2,
+                },
+            },
+        ];
+
+        let (mut storage, snapshot, recovered) =
+            Storage::open(config.clone(), CodecKind::Json).unwrap();
+        assert_eq!(snapshot, Snapshot::default());
+        assert!(recovered.is_empty());
+        entries
+            .iter()
+            .for_each(|entry| storage.append(entry).unwrap());
+        drop(storage);
+
+        // Entries written with another codec still decode
+        let (_, _, recovered) = Storage::open(config, CodecKind::MessagePack).unwrap();
+        assert_eq!(recovered, entries);
+    }
+
+    #[test]
+    fn torn_write_is_truncated() {
+        let config = config("torn-write");
+        let (mut storage, _, _) = Storage::open(config.clone(), CodecKind::Json).unwrap();
+        storage.append(&added(1, 1)).unwrap();
+        storage.append(&added(2, 2)).unwrap();
+        drop(storage);
+        let valid_len = wal_len(&config);
+
+        // A frame announcing 100 bytes of which only 10 made it to disk
+        append_raw(&config, &100u32.to_be_bytes());
+        append_raw(&config, &[0; 10]);
+
+        let (mut storage, _, recovered) = Storage::open(config.clone(), CodecKind::Json).unwrap();
+        assert_eq!(recovered, vec![added(1, 1), added(2, 2)]);
+        assert_eq!(wal_len(&config), valid_len);
+
+        // Appends after the truncation are read back
+        storage.append(&added(3, 3)).unwrap();
+        drop(storage);
+        let (_, _, recovered) = Storage::open(config, CodecKind::Json).unwrap();
+        assert_eq!(recovered, vec![added(1, 1), added(2, 2), added(3, 3)]);
+    }
+
+    #[test]
+    fn undecodable_tail_is_truncated() {
+        let config = config("undecodable-tail");
+        let (mut storage, _, _) = Storage::open(config.clone(), CodecKind::Json).unwrap();
+        storage.append(&added(1, 1)).unwrap();
+        drop(storage);
+        let valid_len = wal_len(&config);
+
+        // A complete frame whose content was never a log entry
+        let garbage = [0, b'{', b'x'];
+        append_raw(&config, &(garbage.len() as u32).to_be_bytes());
+        append_raw(&config, &garbage);
+
+        let (_, _, recovered) = Storage::open(config.clone(), CodecKind::Json).unwrap();
+        assert_eq!(recovered, vec![added(1, 1)]);
+        assert_eq!(wal_len(&config), valid_len);
+    }
+
+    #[test]
+    fn snapshot_replaces_the_log() {
+        let config = config("snapshot");
+        let (mut storage, _, _) = Storage::open(config.clone(), CodecKind::Json).unwrap();
+        storage.append(&added(1, 1)).unwrap();
+        storage.append(&added(2, 2)).unwrap();
+
+        let mut processes = HashMap::new();
+        processes.insert(1, ServiceInstance::new("test", "1"));
+        let snapshot = Snapshot {
+            revision: 2,
+            revision_term: 1,
+            last_registered_id: 2,
+            processes,
+            consensus: None,
+            term: 1,
+            voted_for: Some(1),
+        };
+        storage
+            .write_snapshot(&snapshot, storage.log_len().unwrap())
+            .unwrap();
+        assert_eq!(wal_len(&config), 0);
+        storage.append(&added(3, 3)).unwrap();
+        drop(storage);
+
+        let (_, recovered_snapshot, recovered) = Storage::open(config, CodecKind::Json).unwrap();
+        assert_eq!(recovered_snapshot, snapshot);
+        assert_eq!(recovered, vec![added(3, 3)]);
+    }
+
+    #[test]
+    fn snapshot_keeps_entries_logged_while_it_was_taken() {
+        let config = config("snapshot-tail");
+        let (mut storage, _, _) = Storage::open(config.clone(), CodecKind::Json).unwrap();
+        storage.append(&added(1, 1)).unwrap();
+        let logged_before = storage.log_len().unwrap();
+        storage.append(&added(2, 2)).unwrap();
+
+        let snapshot = Snapshot {
+            revision: 1,
+            revision_term: 1,
+            last_registered_id: 1,
+            ..Snapshot::default()
+        };
+        storage.write_snapshot(&snapshot, logged_before).unwrap();
+        storage.append(&added(3, 3)).unwrap();
+        drop(storage);
+
+        let (_, _, recovered) = Storage::open(config, CodecKind::Json).unwrap();
+        assert_eq!(recovered, vec![added(2, 2), added(3, 3)]);
+    }
+
+    #[test]
+    fn entries_covered_by_the_snapshot_are_skipped() {
+        // A crash after the snapshot was renamed into place but before the
+        // log was emptied
+        let config = config("crash-after-snapshot");
+        let (mut storage, _, _) = Storage::open(config.clone(), CodecKind::Json).unwrap();
+        [added(1, 1), added(2, 2), added(3, 3)]
+            .iter()
+            .for_each(|entry| storage.append(entry).unwrap());
+        drop(storage);
+        let snapshot = Snapshot {
+            revision: 2,
+            last_registered_id: 2,
+            ..Snapshot::default()
+        };
+        fs::write(
+            config.data_dir.join(SNAPSHOT_FILE),
+            encode(CodecKind::Json, &snapshot).unwrap(),
+        )
+        .unwrap();
+
+        let (_, recovered_snapshot, recovered) = Storage::open(config, CodecKind::Json).unwrap();
+        assert_eq!(recovered_snapshot, snapshot);
+        assert_eq!(recovered, vec![added(3, 3)]);
+    }
+
+    #[test]
+    fn corrupt_snapshot_is_an_error() {
+        let config = config("corrupt-snapshot");
+        fs::create_dir_all(&config.data_dir).unwrap();
+        fs::write(config.data_dir.join(SNAPSHOT_FILE), [0, b'{']).unwrap();
+
+        let error = Storage::open(config, CodecKind::Json).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
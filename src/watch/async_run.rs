@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::{self, JoinSet},
+    time,
+};
+
+use super::{Watch, WATCH_RENEW_INTERVAL};
+use crate::{algorithms::Logger, async_algorithms::AsyncP2PSend, shutdown::POLL_INTERVAL};
+
+impl AsyncP2PSend for Watch {}
+
+impl Watch {
+    /// Same as [`Watch::run`], with every connection running as a task on
+    /// the current tokio runtime.
+    pub async fn run_async(self: Arc<Self>) -> std::io::Result<()> {
+        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.port)).await?;
+        let mut tasks = JoinSet::new();
+
+        // Periodically renew the watch, from the last revision seen
+        let watch = self.clone();
+        tasks.spawn(async move {
+            loop {
+                watch.subscribe_async().await;
+                if watch
+                    .shutdown
+                    .wait_timeout_async(WATCH_RENEW_INTERVAL)
+                    .await
+                {
+                    break;
+                }
+            }
+        });
+
+        // Listen for pushed changes
+        while !self.shutdown.is_requested() {
+            match time::timeout(POLL_INTERVAL, listener.accept()).await {
+                Ok(Ok((stream, _))) => {
+                    let watch = self.clone();
+                    tasks.spawn(async move { watch.handle_connection_async(stream).await });
+                }
+                Ok(Err(e)) => self.log(&format!("Couldn't accept connection: {}", e)),
+                Err(_) => {}
+            }
+        }
+
+        while tasks.join_next().await.is_some() {}
+        self.log("Stopped");
+        Ok(())
+    }
+
+    async fn handle_connection_async(self: &Arc<Self>, stream: TcpStream) {
+        let handle = |envelope| async move {
+            // Changes are handed to the caller's callback, which may block
+            let watch = self.clone();
+            let resync = task::spawn_blocking(move || watch.handle_envelope(envelope))
+                .await
+                .unwrap_or_else(|e| {
+                    self.log(&format!("Couldn't handle envelope: {}", e));
+                    false
+                });
+            if resync {
+                self.subscribe_async().await;
+            }
+            None
+        };
+        Watch::serve_frames_async(
+            stream,
+            &self.shutdown,
+            self.as_ref(),
+            self.codec,
+            self.max_frame_size,
+            handle,
+            |_| None,
+        )
+        .await;
+    }
+
+    async fn subscribe_async(&self) {
+        if let Err(e) = Watch::send_async(
+            &self.registry_address,
+            &self.watch_event(),
+            self.max_frame_size,
+        )
+        .await
+        {
+            self.log(&format!("Couldn't watch registry: {}", e));
+        }
+    }
+}
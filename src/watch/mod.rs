@@ -0,0 +1,223 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    algorithms::{Logger, P2PSend},
+    codec::CodecKind,
+    events::{Envelope, Event, ProcessEvent, RegistryEvent},
+    service::{MembershipDelta, ServiceInstance},
+    shutdown::{Shutdown, ShutdownHandle, POLL_INTERVAL},
+};
+
+#[cfg(feature = "async")]
+mod async_run;
+
+/// How often a watch is renewed, so a registry that restarted or dropped it
+/// after a failed push starts pushing again.
+pub const WATCH_RENEW_INTERVAL: Duration = Duration::from_secs(10);
+
+type OnChange = dyn Fn(&HashMap<u32, ServiceInstance>) + Send + Sync;
+
+/// Instances of one service, or of every service, kept up to date from the
+/// changes the registry pushes to a listener on `port`.
+pub struct Watch {
+    registry_address: String,
+    service_name: Option<String>,
+    port: u32,
+    codec: CodecKind,
+    max_frame_size: usize,
+    /// Revision of the registry's table `instances` matches.
+    revision: Mutex<Option<u64>>,
+    instances: Mutex<HashMap<u32, ServiceInstance>>,
+    on_change: Box<OnChange>,
+    shutdown: Arc<Shutdown>,
+}
+
+impl P2PSend for Watch {}
+
+impl Watch {
+    /// `on_change` is called with the watched instances every time they
+    /// change, starting with the snapshot the registry sends first. Frames
+    /// over `max_frame_size` bytes are neither sent nor read.
+    pub fn new(
+        registry_address: &str,
+        service_name: Option<&str>,
+        port: u32,
+        codec: CodecKind,
+        max_frame_size: usize,
+        on_change: impl Fn(&HashMap<u32, ServiceInstance>) + Send + Sync + 'static,
+    ) -> Self {
+        Watch {
+            registry_address: registry_address.to_owned(),
+            service_name: service_name.map(str::to_owned),
+            port,
+            codec,
+            max_frame_size,
+            revision: Mutex::new(None),
+            instances: Mutex::new(HashMap::new()),
+            on_change: Box::new(on_change),
+            shutdown: Arc::new(Shutdown::default()),
+        }
+    }
+
+    /// Watches until shut down.
+    pub fn run(&self) -> std::io::Result<()> {
+        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.port))?;
+        self.serve(listener)
+    }
+
+    /// Watches on a background thread, returning once it listens.
+    pub fn start(self) -> std::io::Result<ShutdownHandle> {
+        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.port))?;
+        let shutdown = self.shutdown.clone();
+        let thread = thread::spawn(move || self.serve(listener));
+
+        Ok(ShutdownHandle::new(shutdown, thread))
+    }
+
+    fn serve(&self, listener: TcpListener) -> std::io::Result<()> {
+        listener.set_nonblocking(true)?;
+        thread::scope(|s| {
+            // Periodically renew the watch, from the last revision seen
+            s.spawn(move || loop {
+                self.subscribe();
+                if self.shutdown.wait_timeout(WATCH_RENEW_INTERVAL) {
+                    break;
+                }
+            });
+
+            // Listen for pushed changes
+            while !self.shutdown.is_requested() {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        s.spawn(move || self.handle_connection(stream));
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        self.shutdown.wait_timeout(POLL_INTERVAL);
+                    }
+                    Err(e) => self.log(&format!("Couldn't accept connection: {}", e)),
+                }
+            }
+        });
+
+        self.log("Stopped");
+        Ok(())
+    }
+
+    fn handle_connection(&self, stream: TcpStream) {
+        let handle = |envelope| {
+            if self.handle_envelope(envelope) {
+                self.subscribe();
+            }
+            None
+        };
+        Watch::serve_frames(
+            stream,
+            &self.shutdown,
+            self,
+            self.codec,
+            self.max_frame_size,
+            handle,
+            |_| None,
+        );
+    }
+
+    fn subscribe(&self) {
+        if let Err(e) = Watch::send(
+            &self.registry_address,
+            &self.watch_event(),
+            self.max_frame_size,
+        ) {
+            self.log(&format!("Couldn't watch registry: {}", e));
+        }
+    }
+
+    fn watch_event(&self) -> Vec<u8> {
+        Envelope::new(
+            None,
+            ProcessEvent::Watch {
+                service_name: self.service_name.clone(),
+                port: self.port,
+                since: *self.revision.lock().unwrap(),
+            },
+        )
+        .as_bytes_vec(self.codec)
+    }
+
+    /// Applies a pushed change. Returns whether the watch must be renewed
+    /// right away, to start over from a snapshot after missing changes.
+    fn handle_envelope(&self, envelope: Envelope) -> bool {
+        match envelope.event {
+            // Taken even if older, a registry restarted without its log
+            // counts revisions from scratch again
+            Event::RegistryEvent(RegistryEvent::MembershipSnapshot {
+                revision,
+                instances,
+            }) => {
+                *self.revision.lock().unwrap() = Some(revision);
+                let watched = &mut *self.instances.lock().unwrap();
+                *watched = instances;
+                (self.on_change)(watched);
+                false
+            }
+            Event::RegistryEvent(RegistryEvent::MembershipDeltas {
+                since,
+                revision,
+                deltas,
+            }) => {
+                if self.apply_deltas(since, revision, deltas) {
+                    return false;
+                }
+                self.log(&format!(
+                    "Missed changes before revision {}, resyncing",
+                    since
+                ));
+                *self.revision.lock().unwrap() = None;
+                true
+            }
+            _ => {
+                self.log("Unexpected message");
+                false
+            }
+        }
+    }
+
+    /// Returns false if changes between the current revision and `since`
+    /// are missing.
+    fn apply_deltas(&self, since: u64, revision: u64, deltas: Vec<MembershipDelta>) -> bool {
+        let current_revision = &mut *self.revision.lock().unwrap();
+        match *current_revision {
+            Some(current) if since <= current && current < revision => {
+                let watched = &mut *self.instances.lock().unwrap();
+                deltas
+                    .iter()
+                    .filter(|delta| delta.revision > current)
+                    .for_each(|delta| delta.change.apply(watched));
+                *current_revision = Some(revision);
+                (self.on_change)(watched);
+                true
+            }
+            Some(current) => revision <= current,
+            None => false,
+        }
+    }
+}
+
+impl Logger for Watch {
+    fn what_is_self(&self) -> String {
+        format!(
+            "Watch {}",
+            self.service_name.as_deref().unwrap_or("every service")
+        )
+    }
+
+    fn what_is_id(&self) -> Option<u32> {
+        None
+    }
+}